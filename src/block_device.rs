@@ -6,4 +6,134 @@ pub trait BlockDevice {
     fn flush(&self) -> Result<(), Self::Error>;
     fn count(&self) -> Result<u32, Self::Error>;
     fn lba_size(&self) -> Result<usize, Self::Error>;
-}
\ No newline at end of file
+}
+
+// exposes any seekable embedded-io stream, such as a disk image, as a block device
+#[cfg(feature = "embedded-io")]
+pub struct IoBlockDevice<T> {
+    io: core::cell::RefCell<T>,
+    lba_count: u32,
+    lba_size: usize,
+}
+
+#[cfg(feature = "embedded-io")]
+impl <T: embedded_io::Read + embedded_io::Write + embedded_io::Seek> IoBlockDevice<T> {
+    pub fn new(mut io: T, lba_size: usize) -> Result<Self, T::Error> {
+        let lba_count = (io.seek(embedded_io::SeekFrom::End(0))? / lba_size as u64) as u32;
+
+        Ok(Self {
+            io: core::cell::RefCell::new(io),
+            lba_count,
+            lba_size,
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io.into_inner()
+    }
+
+    fn seek(&self, io: &mut T, lba: u32, offset: usize) -> Result<(), T::Error> {
+        io.seek(embedded_io::SeekFrom::Start(lba as u64 * self.lba_size as u64 + offset as u64))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl <T: embedded_io::Read + embedded_io::Write + embedded_io::Seek> BlockDevice for IoBlockDevice<T> {
+    type Error = T::Error;
+
+    fn read(&self, lba: u32, offset: usize, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut io = self.io.borrow_mut();
+        self.seek(&mut io, lba, offset)?;
+        let mut len = 0;
+
+        while len != buf.len() {
+            match io.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn write(&self, lba: u32, offset: usize, buf: &[u8]) -> Result<usize, Self::Error> {
+        let mut io = self.io.borrow_mut();
+        self.seek(&mut io, lba, offset)?;
+        io.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.io.borrow_mut().flush()
+    }
+
+    fn count(&self) -> Result<u32, Self::Error> {
+        Ok(self.lba_count)
+    }
+
+    fn lba_size(&self) -> Result<usize, Self::Error> {
+        Ok(self.lba_size)
+    }
+}
+
+// sectors kept in memory, for the tests
+#[cfg(test)]
+pub(crate) struct RamDevice {
+    data: core::cell::RefCell<std::vec::Vec<u8>>,
+    lba_size: usize,
+    reads: core::cell::Cell<usize>,
+    writes: core::cell::Cell<usize>,
+}
+
+#[cfg(test)]
+impl RamDevice {
+    pub fn new(count: u32, lba_size: usize) -> Self {
+        Self {
+            data: core::cell::RefCell::new(std::vec![0u8; count as usize * lba_size]),
+            lba_size,
+            reads: core::cell::Cell::new(0),
+            writes: core::cell::Cell::new(0),
+        }
+    }
+
+    // calls made so far
+    pub fn reads(&self) -> usize {
+        self.reads.get()
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes.get()
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for RamDevice {
+    type Error = ();
+
+    fn read(&self, lba: u32, offset: usize, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let pos = lba as usize * self.lba_size + offset;
+        self.reads.set(self.reads.get() + 1);
+        buf.copy_from_slice(self.data.borrow().get(pos..pos + buf.len()).ok_or(())?);
+        Ok(buf.len())
+    }
+
+    fn write(&self, lba: u32, offset: usize, buf: &[u8]) -> Result<usize, Self::Error> {
+        let pos = lba as usize * self.lba_size + offset;
+        self.writes.set(self.writes.get() + 1);
+        self.data.borrow_mut().get_mut(pos..pos + buf.len()).ok_or(())?.copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn count(&self) -> Result<u32, Self::Error> {
+        Ok((self.data.borrow().len() / self.lba_size) as u32)
+    }
+
+    fn lba_size(&self) -> Result<usize, Self::Error> {
+        Ok(self.lba_size)
+    }
+}
@@ -0,0 +1,290 @@
+use core::cell::{Cell, RefCell};
+use super::block_device::BlockDevice;
+use super::Error;
+
+struct Entry<const S: usize> {
+    lba: u32,
+    buf: [u8; S],
+    is_valid: bool,
+    is_dirty: bool,
+    last_use: u32,
+}
+
+// cached buffers identified by a key, shared with the FAT cache
+pub(crate) trait Slot {
+    fn is_valid(&self) -> bool;
+    fn key(&self) -> u32;
+    fn last_use(&self) -> u32;
+}
+
+impl <const S: usize> Slot for Entry<S> {
+    fn is_valid(&self) -> bool {
+        self.is_valid
+    }
+
+    fn key(&self) -> u32 {
+        self.lba
+    }
+
+    fn last_use(&self) -> u32 {
+        self.last_use
+    }
+}
+
+// `Ok` with the slot holding `key`, otherwise `Err` with the one to reuse:
+// a free slot or the least recently used one, ages are compared relative to
+// the current tick so they survive it wrapping around
+pub(crate) fn find_slot<T: Slot>(slots: &[T], key: u32, tick: u32) -> Result<usize, usize> {
+    if let Some(i) = slots.iter().position(|s| s.is_valid() && s.key() == key) {
+        return Ok(i);
+    }
+
+    if let Some(i) = slots.iter().position(|s| !s.is_valid()) {
+        return Err(i);
+    }
+
+    let mut oldest = 0;
+
+    for (i, s) in slots.iter().enumerate() {
+        if tick.wrapping_sub(s.last_use()) > tick.wrapping_sub(slots[oldest].last_use()) {
+            oldest = i;
+        }
+    }
+
+    Err(oldest)
+}
+
+// keeps the last N used sectors of S bytes in memory, dirty sectors are written
+// back on eviction and on flush, nothing is written back on drop
+pub struct SectorCache<D, const N: usize, const S: usize = 512> {
+    dev: D,
+    entries: RefCell<[Entry<S>; N]>,
+    tick: Cell<u32>,
+}
+
+impl <D: BlockDevice, const N: usize, const S: usize> SectorCache<D, N, S> {
+    pub fn new(dev: D) -> Result<Self, Error> {
+        if dev.lba_size().or(Err(Error::DeviceIO))? != S || N == 0 {
+            return Err(Error::UnsupportedSectorSize);
+        }
+
+        Ok(Self {
+            dev,
+            entries: RefCell::new(core::array::from_fn(|_| Entry {
+                lba: 0,
+                buf: [0u8; S],
+                is_valid: false,
+                is_dirty: false,
+                last_use: 0,
+            })),
+            tick: Cell::new(0),
+        })
+    }
+
+    pub fn into_inner(self) -> Result<D, D::Error> {
+        self.flush()?;
+        Ok(self.dev)
+    }
+
+    // returns the index of the entry holding `lba`, loading it when `load` is set
+    fn entry(&self, entries: &mut [Entry<S>; N], lba: u32, load: bool) -> Result<usize, D::Error> {
+        let tick = self.tick.get().wrapping_add(1);
+        self.tick.set(tick);
+
+        let i = match find_slot(entries, lba, tick) {
+            Ok(i) => {
+                entries[i].last_use = tick;
+                return Ok(i);
+            },
+            Err(i) => i,
+        };
+
+        let entry = &mut entries[i];
+
+        if entry.is_valid && entry.is_dirty {
+            self.dev.write(entry.lba, 0, &entry.buf)?;
+        }
+
+        entry.is_valid = false;
+        entry.is_dirty = false;
+
+        if load {
+            self.dev.read(lba, 0, &mut entry.buf)?;
+        }
+
+        entry.lba = lba;
+        entry.is_valid = true;
+        entry.last_use = tick;
+        Ok(i)
+    }
+}
+
+impl <D: BlockDevice, const N: usize, const S: usize> BlockDevice for SectorCache<D, N, S> {
+    type Error = D::Error;
+
+    fn read(&self, lba: u32, offset: usize, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut entries = self.entries.borrow_mut();
+        let mut bytes_read = 0;
+
+        while bytes_read != buf.len() {
+            let pos = offset + bytes_read;
+            let sector_offset = pos % S;
+            let len = core::cmp::min(buf.len() - bytes_read, S - sector_offset);
+            let i = self.entry(&mut entries, lba + (pos / S) as u32, true)?;
+            buf[bytes_read..bytes_read + len].copy_from_slice(&entries[i].buf[sector_offset..sector_offset + len]);
+            bytes_read += len;
+        }
+
+        Ok(bytes_read)
+    }
+
+    fn write(&self, lba: u32, offset: usize, buf: &[u8]) -> Result<usize, Self::Error> {
+        let mut entries = self.entries.borrow_mut();
+        let mut bytes_written = 0;
+
+        while bytes_written != buf.len() {
+            let pos = offset + bytes_written;
+            let sector_offset = pos % S;
+            let len = core::cmp::min(buf.len() - bytes_written, S - sector_offset);
+            // a sector overwritten as a whole doesn't have to be read first
+            let i = self.entry(&mut entries, lba + (pos / S) as u32, len != S)?;
+            entries[i].buf[sector_offset..sector_offset + len].copy_from_slice(&buf[bytes_written..bytes_written + len]);
+            entries[i].is_dirty = true;
+            bytes_written += len;
+        }
+
+        Ok(bytes_written)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        let mut entries = self.entries.borrow_mut();
+
+        for entry in entries.iter_mut() {
+            if entry.is_valid && entry.is_dirty {
+                self.dev.write(entry.lba, 0, &entry.buf)?;
+                entry.is_dirty = false;
+            }
+        }
+
+        self.dev.flush()
+    }
+
+    fn count(&self) -> Result<u32, Self::Error> {
+        self.dev.count()
+    }
+
+    fn lba_size(&self) -> Result<usize, Self::Error> {
+        Ok(S)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_device::RamDevice;
+
+    fn cache<const N: usize>() -> SectorCache<RamDevice, N> {
+        SectorCache::new(RamDevice::new(64, 512)).unwrap()
+    }
+
+    fn inner_byte(cache: &SectorCache<RamDevice, 2>, lba: u32, offset: usize) -> u8 {
+        let mut buf = [0u8; 1];
+        cache.dev.read(lba, offset, &mut buf).unwrap();
+        buf[0]
+    }
+
+    #[test]
+    fn write_back_on_eviction() {
+        let cache = cache::<2>();
+        cache.write(5, 10, &[0xA5]).unwrap();
+        cache.read(6, 0, &mut [0u8; 4]).unwrap();
+        assert_eq!(cache.dev.writes(), 0);
+
+        cache.read(7, 0, &mut [0u8; 4]).unwrap();
+        assert_eq!(cache.dev.writes(), 1);
+        assert_eq!(inner_byte(&cache, 5, 10), 0xA5);
+    }
+
+    #[test]
+    fn nothing_written_before_flush() {
+        let cache = cache::<2>();
+        cache.write(1, 0, &[1, 2, 3]).unwrap();
+        cache.write(2, 100, &[4, 5, 6]).unwrap();
+        cache.write(1, 3, &[7]).unwrap();
+        assert_eq!(cache.dev.writes(), 0);
+        assert_eq!(inner_byte(&cache, 1, 3), 0);
+
+        cache.flush().unwrap();
+        assert_eq!(cache.dev.writes(), 2);
+        assert_eq!(inner_byte(&cache, 1, 3), 7);
+        assert_eq!(inner_byte(&cache, 2, 102), 6);
+
+        // clean sectors aren't written again
+        cache.write(3, 0, &[8]).unwrap();
+        let dev = cache.into_inner().unwrap();
+        assert_eq!(dev.writes(), 3);
+    }
+
+    #[test]
+    fn full_sector_write_skips_read() {
+        let cache = cache::<2>();
+        cache.write(4, 0, &[0x5A; 512]).unwrap();
+        assert_eq!(cache.dev.reads(), 0);
+
+        cache.write(5, 1, &[0x5A; 511]).unwrap();
+        assert_eq!(cache.dev.reads(), 1);
+    }
+
+    #[test]
+    fn span_sectors() {
+        let cache = cache::<4>();
+        let mut data = [0u8; 1000];
+
+        for (i, c) in data.iter_mut().enumerate() {
+            *c = i as u8;
+        }
+
+        // the end of sector 3, all of 4 and the start of 5
+        assert_eq!(cache.write(3, 300, &data).unwrap(), data.len());
+        assert_eq!(cache.dev.reads(), 2);
+
+        let mut buf = [0u8; 1000];
+        assert_eq!(cache.read(3, 300, &mut buf).unwrap(), buf.len());
+        assert_eq!(buf, data);
+
+        let dev = cache.into_inner().unwrap();
+        let mut buf = [0u8; 1000];
+        dev.read(3, 300, &mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn oldest_slot_after_tick_wraps() {
+        let entry = |lba, last_use| Entry::<4> {
+            lba,
+            buf: [0u8; 4],
+            is_valid: true,
+            is_dirty: false,
+            last_use,
+        };
+
+        let entries = [entry(1, 3), entry(2, u32::MAX - 1), entry(3, 1), entry(4, u32::MAX)];
+        assert_eq!(find_slot(&entries, 3, 5), Ok(2));
+        assert_eq!(find_slot(&entries, 9, 5), Err(1));
+
+        let cache = cache::<2>();
+        cache.tick.set(u32::MAX - 1);
+        cache.read(0, 0, &mut [0u8; 1]).unwrap();
+        cache.read(1, 0, &mut [0u8; 1]).unwrap();
+        cache.read(0, 0, &mut [0u8; 1]).unwrap();
+        assert!(cache.tick.get() < 2);
+
+        // sector 1 is the least recently used one even though its tick is higher
+        cache.read(2, 0, &mut [0u8; 1]).unwrap();
+        let reads = cache.dev.reads();
+        cache.read(0, 0, &mut [0u8; 1]).unwrap();
+        assert_eq!(cache.dev.reads(), reads);
+        cache.read(1, 0, &mut [0u8; 1]).unwrap();
+        assert_eq!(cache.dev.reads(), reads + 1);
+    }
+}
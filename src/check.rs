@@ -0,0 +1,517 @@
+use super::{ClusterValue, FileSystem, Error};
+use super::block_device::BlockDevice;
+use super::code_page::OemCodePage;
+use super::dir_entry::{self, DIR_ENTRY_SIZE, FREE_ENTRY, REMOVED_ENTRY};
+use super::dir_iterator::DirIterator;
+use super::fs::Fs;
+use super::lfn;
+use super::stream::Stream;
+use super::time::TimeSource;
+
+// clusters are tracked in a bitmap given by the caller, one bit for each, when
+// it's too small for all of them the volume is walked once for each window of
+// clusters it covers, with at least this many in a window
+const WINDOW: u32 = 4096;
+const MAX_DEPTH: usize = 32;
+
+// entries are named by their first cluster and directories by theirs,
+// 0 being the FAT12/16 root directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Problem {
+    // allocated in the FAT but not part of any file or directory
+    LostCluster { cluster: u32 },
+    // part of more than one chain, or more than once of the same chain
+    CrossLinked { cluster: u32 },
+    // a chain goes out of the data area, `cluster` is where it points to
+    InvalidCluster { first_cluster: u32, cluster: u32 },
+    ChainEndsInFree { first_cluster: u32, cluster: u32 },
+    ChainEndsInBad { first_cluster: u32, cluster: u32 },
+    ChainLoop { first_cluster: u32 },
+    SizeMismatch { first_cluster: u32, size: u32, clusters: u32 },
+    BadDotEntry { dir: u32 },
+    BadDotDotEntry { dir: u32 },
+    // long name entries without the short entry they belong to, `entry` is
+    // the index of the first one in the directory
+    OrphanLfn { dir: u32, entry: u32 },
+    FatCopyMismatch { copy: u32, sector: u32 },
+    // directories nested deeper than this are not checked
+    TooDeep { dir: u32 },
+}
+
+// the first `N` problems found and how many there were in total
+pub struct Report<const N: usize> {
+    problems: [Option<Problem>; N],
+    count: usize,
+}
+
+impl <const N: usize> Report<N> {
+    pub fn problems(&self) -> impl Iterator<Item = &Problem> {
+        self.problems.iter().flatten()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.count == 0
+    }
+}
+
+// length of the bitmap covering every cluster of the volume in one pass
+pub fn bitmap_len<D: BlockDevice, C: OemCodePage, T: TimeSource>(fs: &Fs<D, C, T>) -> usize {
+    (fs.cluster_count() as usize).div_ceil(32)
+}
+
+pub fn check<const N: usize, D: BlockDevice, C: OemCodePage, T: TimeSource>(fs: &Fs<D, C, T>, used: &mut [u32]) -> Result<Report<N>, Error> {
+    let mut report = Report {
+        problems: [None; N],
+        count: 0,
+    };
+
+    check_with(fs, used, |problem| {
+        if let Some(slot) = report.problems.get_mut(report.count) {
+            *slot = Some(problem);
+        }
+
+        report.count += 1;
+    })?;
+
+    Ok(report)
+}
+
+// every problem goes to `report` as soon as it's found, nothing gets repaired
+pub fn check_with<D: BlockDevice, C: OemCodePage, T: TimeSource>(fs: &Fs<D, C, T>, used: &mut [u32], mut report: impl FnMut(Problem)) -> Result<(), Error> {
+    // the copies are compared on the media
+    fs.flush()?;
+
+    for copy in 1..fs.fats_count() {
+        if let Some(sector) = fs.fat_copy_mismatch(copy)? {
+            report(Problem::FatCopyMismatch { copy, sector });
+        }
+    }
+
+    let mut window_buf = [0u32; WINDOW as usize / 32];
+    let used = if used.len() < window_buf.len() { &mut window_buf[..] } else { used };
+    let len = core::cmp::min(used.len(), bitmap_len(fs));
+    let used = &mut used[..len];
+    let window = used.len() as u32 * 32;
+    let mut first = 2;
+
+    while first < fs.cluster_count() + 2 {
+        used.fill(0);
+
+        let mut checker = Checker {
+            fs,
+            first,
+            window,
+            used: &mut *used,
+            is_first_pass: first == 2,
+            report: &mut report,
+        };
+
+        checker.walk()?;
+        checker.lost_clusters()?;
+        first += window;
+    }
+
+    Ok(())
+}
+
+struct Checker<'a, F, R> {
+    fs: &'a F,
+    first: u32,
+    window: u32,
+    used: &'a mut [u32],
+    // problems of entries are the same in every pass, they are reported once
+    is_first_pass: bool,
+    report: &'a mut R,
+}
+
+impl <'a, F: FileSystem, R: FnMut(Problem)> Checker<'a, F, R> {
+    fn report_once(&mut self, problem: Problem) {
+        if self.is_first_pass {
+            (self.report)(problem);
+        }
+    }
+
+    fn is_valid(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.fs.cluster_count() + 2
+    }
+
+    // `true` if the cluster was already marked
+    fn mark(&mut self, cluster: u32) -> bool {
+        if cluster < self.first || cluster - self.first >= self.window {
+            return false;
+        }
+
+        let n = (cluster - self.first) as usize;
+        let is_used = self.used[n / 32] & (1 << (n % 32)) != 0;
+        self.used[n / 32] |= 1 << (n % 32);
+        is_used
+    }
+
+    // marks the clusters of a chain and returns its length
+    fn chain(&mut self, first_cluster: u32) -> Result<u32, Error> {
+        let mut cluster = first_cluster;
+        let mut len = 0;
+        let mut is_cross_linked = false;
+
+        loop {
+            if !self.is_valid(cluster) {
+                self.report_once(Problem::InvalidCluster { first_cluster, cluster });
+                return Ok(len);
+            }
+
+            len += 1;
+
+            // a chain running in circles shows up here only once
+            if !is_cross_linked && self.mark(cluster) {
+                (self.report)(Problem::CrossLinked { cluster });
+                is_cross_linked = true;
+            }
+
+            if len > self.fs.cluster_count() {
+                self.report_once(Problem::ChainLoop { first_cluster });
+                return Ok(len);
+            }
+
+            match self.fs.fat_table_get(cluster)? {
+                ClusterValue::Next(next) => cluster = next,
+                ClusterValue::Last => return Ok(len),
+                ClusterValue::Free => {
+                    self.report_once(Problem::ChainEndsInFree { first_cluster, cluster });
+                    return Ok(len);
+                },
+                ClusterValue::Bad => {
+                    self.report_once(Problem::ChainEndsInBad { first_cluster, cluster });
+                    return Ok(len);
+                },
+            }
+        }
+    }
+
+    // directories are walked depth first, the stack keeps where each one was left
+    fn walk(&mut self) -> Result<(), Error> {
+        let root = self.fs.root_cluster();
+
+        if root != 0 {
+            self.chain(root)?;
+        }
+
+        self.orphan_lfns(root)?;
+
+        let mut stack = [(0u32, Stream::open(0)); MAX_DEPTH];
+        stack[0] = (root, Stream::open(root));
+        let mut depth = 1;
+
+        while depth != 0 {
+            let (dir, pos) = stack[depth - 1];
+            let mut iter = DirIterator::resume(self.fs, pos);
+            let next = iter.next();
+            stack[depth - 1].1 = iter.position();
+
+            let dir_entry = match next {
+                Some(Ok((dir_entry, _))) => dir_entry,
+                // the broken chain has been reported already
+                Some(Err(Error::UnexpectedClusterValue | Error::InvalidClusterNumber)) | None => {
+                    depth -= 1;
+                    continue;
+                },
+                Some(Err(e)) => {
+                    return Err(e);
+                },
+            };
+
+            if dir_entry.compare(".") || dir_entry.compare("..") {
+                continue;
+            }
+
+            let cluster = dir_entry.cluster();
+
+            if dir_entry.is_file() {
+                let len = if cluster == 0 { 0 } else { self.chain(cluster)? };
+                let expected = (dir_entry.size() as usize).div_ceil(self.fs.cluster_size()) as u32;
+
+                // empty files may keep a cluster
+                if len != expected && !(expected == 0 && len == 1) {
+                    self.report_once(Problem::SizeMismatch { first_cluster: cluster, size: dir_entry.size(), clusters: len });
+                }
+
+                continue;
+            }
+
+            self.chain(cluster)?;
+
+            if !self.is_valid(cluster) {
+                continue;
+            }
+
+            if depth == MAX_DEPTH {
+                self.report_once(Problem::TooDeep { dir: cluster });
+                continue;
+            }
+
+            self.dot_entries(cluster, if dir == root { 0 } else { dir })?;
+            self.orphan_lfns(cluster)?;
+            stack[depth] = (cluster, Stream::open(cluster));
+            depth += 1;
+        }
+
+        Ok(())
+    }
+
+    fn dot_entries(&mut self, dir: u32, parent: u32) -> Result<(), Error> {
+        if !self.is_first_pass {
+            return Ok(());
+        }
+
+        let mut iter = DirIterator::new(self.fs, dir);
+
+        match iter.next() {
+            Some(Ok((dir_entry, _))) if dir_entry.compare(".") && dir_entry.cluster() == dir => {},
+            _ => (self.report)(Problem::BadDotEntry { dir }),
+        }
+
+        match iter.next() {
+            Some(Ok((dir_entry, _))) if dir_entry.compare("..") && dir_entry.cluster() == parent => {},
+            _ => (self.report)(Problem::BadDotDotEntry { dir }),
+        }
+
+        Ok(())
+    }
+
+    // long names are followed by their short entry, which has to match the checksum
+    fn orphan_lfns(&mut self, dir: u32) -> Result<(), Error> {
+        if !self.is_first_pass {
+            return Ok(());
+        }
+
+        let mut stream = Stream::open(dir);
+        // index of the first entry and checksum of the long name being read
+        let mut lfn: Option<(u32, u8)> = None;
+        let mut entry = 0;
+
+        loop {
+            let mut buf = [0u8; DIR_ENTRY_SIZE];
+
+            match stream.read(self.fs, &mut buf) {
+                Ok(DIR_ENTRY_SIZE) => {},
+                Ok(_) | Err(Error::UnexpectedClusterValue | Error::InvalidClusterNumber) => break,
+                Err(e) => {
+                    return Err(e);
+                },
+            }
+
+            if buf[0] == FREE_ENTRY {
+                break;
+            }
+
+            if buf[0] == REMOVED_ENTRY {
+                if let Some((first, _)) = lfn.take() {
+                    (self.report)(Problem::OrphanLfn { dir, entry: first });
+                }
+            } else if lfn::is_lfn_entry(&buf) {
+                match lfn {
+                    Some((_, checksum)) if !lfn::is_last_lfn_entry(&buf) && buf[13] == checksum => {},
+                    _ => {
+                        if let Some((first, _)) = lfn {
+                            (self.report)(Problem::OrphanLfn { dir, entry: first });
+                        }
+
+                        lfn = Some((entry, buf[13]));
+                    },
+                }
+            } else if let Some((first, checksum)) = lfn.take() {
+                if dir_entry::checksum(&buf) != checksum {
+                    (self.report)(Problem::OrphanLfn { dir, entry: first });
+                }
+            }
+
+            entry += 1;
+        }
+
+        if let Some((first, _)) = lfn {
+            (self.report)(Problem::OrphanLfn { dir, entry: first });
+        }
+
+        Ok(())
+    }
+
+    fn lost_clusters(&mut self) -> Result<(), Error> {
+        let last = core::cmp::min(self.first + self.window, self.fs.cluster_count() + 2);
+
+        for cluster in self.first..last {
+            let n = (cluster - self.first) as usize;
+
+            if self.used[n / 32] & (1 << (n % 32)) != 0 {
+                continue;
+            }
+
+            if let ClusterValue::Next(_) | ClusterValue::Last = self.fs.fat_table_get(cluster)? {
+                (self.report)(Problem::LostCluster { cluster });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_device::RamDevice;
+    use crate::dir::Dir;
+    use crate::format::FormatOptions;
+    use crate::FatType;
+
+    fn mount(fat_type: FatType, sectors_count: u32) -> Fs<RamDevice> {
+        let dev = RamDevice::new(sectors_count, 512);
+        Fs::format_with(&dev, &FormatOptions::new().fat_type(fat_type)).unwrap();
+        Fs::mount(dev).unwrap()
+    }
+
+    fn problems(fs: &Fs<RamDevice>) -> Report<8> {
+        let mut used = std::vec![0u32; bitmap_len(fs)];
+        let report = check::<8, _, _, _>(fs, &mut used).unwrap();
+
+        // a bitmap too small for the volume takes a pass for each window
+        let windowed = check::<8, _, _, _>(fs, &mut []).unwrap();
+        assert_eq!(windowed.count(), report.count());
+        assert!(windowed.problems().eq(report.problems()));
+        report
+    }
+
+    fn fill(fs: &Fs<RamDevice>) {
+        let root = Dir::root(fs).unwrap();
+        let data = [0x5Au8; 5000];
+
+        root.create_dir("docs").unwrap();
+        root.create_dir("docs/deeper").unwrap();
+
+        let mut file = root.create_file("docs/a long file name.txt").unwrap();
+        file.write(&data).unwrap();
+        file.close().unwrap();
+
+        let mut file = root.create_file("old.txt").unwrap();
+        file.write(&data[..100]).unwrap();
+        file.close().unwrap();
+
+        root.create_file("empty").unwrap().close().unwrap();
+        root.rename("docs/a long file name.txt", "docs/deeper/renamed file.txt").unwrap();
+        root.replace("empty", "old.txt").unwrap();
+        root.rename("docs", "Documents").unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        for (fat_type, sectors_count) in [(FatType::Fat12, 2048), (FatType::Fat16, 40000), (FatType::Fat32, 70000)] {
+            let fs = mount(fat_type, sectors_count);
+            fill(&fs);
+            assert!(problems(&fs).is_clean());
+
+            let fs = Fs::mount(fs.unmount().unwrap()).unwrap();
+            assert!(problems(&fs).is_clean());
+
+            let root = Dir::root(&fs).unwrap();
+            assert_eq!(root.metadata("Documents/deeper/renamed file.txt").unwrap().size(), 5000);
+            assert_eq!(root.metadata("old.txt").unwrap().size(), 0);
+            assert!(!root.exists("empty").unwrap());
+            assert!(!root.exists("docs").unwrap());
+
+            let mut buf = [0u8; 5001];
+            let mut file = root.open_file("Documents/deeper/renamed file.txt").unwrap();
+            let mut len = 0;
+
+            while let Ok(n @ 1..) = file.read(&mut buf[len..]) {
+                len += n;
+            }
+
+            assert_eq!(len, 5000);
+            assert!(buf[..len].iter().all(|&c| c == 0x5A));
+        }
+    }
+
+    fn create(root: &Dir<Fs<RamDevice>>, name: &str) -> u32 {
+        let mut file = root.create_file(name).unwrap();
+        file.write(&[1u8; 10]).unwrap();
+        file.close().unwrap();
+        root.iter().flatten().find(|(dir_entry, _)| dir_entry.compare(name)).unwrap().0.cluster()
+    }
+
+    #[test]
+    fn lost_cluster() {
+        let fs = mount(FatType::Fat16, 40000);
+        fill(&fs);
+
+        let last = fs.cluster_count() + 1;
+        fs.fat_table_set(last, ClusterValue::Last).unwrap();
+
+        let report = problems(&fs);
+        assert_eq!(report.count(), 1);
+        assert_eq!(report.problems().next(), Some(&Problem::LostCluster { cluster: last }));
+    }
+
+    #[test]
+    fn cross_linked() {
+        let fs = mount(FatType::Fat12, 2048);
+        let root = Dir::root(&fs).unwrap();
+        let a = create(&root, "a");
+        let b = create(&root, "b");
+
+        let (mut dir_entry, _) = root.iter().flatten().find(|(dir_entry, _)| dir_entry.compare("b")).unwrap();
+        dir_entry.set_cluster(a);
+        dir_entry.flush().unwrap();
+
+        let report = problems(&fs);
+        assert_eq!(report.count(), 2);
+        assert!(report.problems().any(|&problem| problem == Problem::CrossLinked { cluster: a }));
+        assert!(report.problems().any(|&problem| problem == Problem::LostCluster { cluster: b }));
+    }
+
+    #[test]
+    fn fat_copy_mismatch() {
+        let fs = mount(FatType::Fat16, 40000);
+        fill(&fs);
+
+        let dev = fs.unmount().unwrap();
+        let mut raw = [0u8; 512];
+        dev.read(0, 0, &mut raw).unwrap();
+        let reserved_sectors_count = u16::from_le_bytes([raw[14], raw[15]]) as u32;
+        let fat_size_in_sectors = u16::from_le_bytes([raw[22], raw[23]]) as u32;
+        dev.write(reserved_sectors_count + fat_size_in_sectors + 1, 100, &[0xFF]).unwrap();
+
+        let report = problems(&Fs::mount(dev).unwrap());
+        assert_eq!(report.count(), 1);
+        assert_eq!(report.problems().next(), Some(&Problem::FatCopyMismatch { copy: 1, sector: 1 }));
+    }
+
+    fn reads_of_check(dev: RamDevice, used: &mut [u32]) -> (RamDevice, usize) {
+        let reads = dev.reads();
+        let fs = Fs::mount(dev).unwrap();
+        check::<8, _, _, _>(&fs, used).unwrap();
+        let dev = fs.unmount().unwrap();
+        let reads = dev.reads() - reads;
+        (dev, reads)
+    }
+
+    #[test]
+    fn single_pass() {
+        let fs = mount(FatType::Fat32, 70000);
+        let root = Dir::root(&fs).unwrap();
+        let mut file = root.create_file("big").unwrap();
+
+        for _ in 0..4096 {
+            file.write(&[0u8; 4096]).unwrap();
+        }
+
+        file.close().unwrap();
+
+        let mut used = std::vec![0u32; bitmap_len(&fs)];
+        let dev = fs.unmount().unwrap();
+        let (dev, single) = reads_of_check(dev, &mut used);
+        let (_, windowed) = reads_of_check(dev, &mut []);
+        // the windowed walk follows the 16 MB chain once for each 4096 clusters
+        assert!(single * 3 < windowed);
+    }
+}
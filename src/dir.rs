@@ -1,10 +1,13 @@
 use super::{FileSystem, Error, dir_entry};
-use super::dir_entry::DirEntry;
+use super::dir_entry::{DirEntry, DIR_ENTRY_SIZE};
 use super::stream::Stream;
 use super::file::File;
 use super::path::Path;
 use super::dir_iterator::DirIterator;
-use super::lfn;
+use super::lfn::{self, Lfn};
+use super::metadata::{Attributes, Metadata};
+use super::time::DateTime;
+use super::short_name::{self, SHORT_NAME_LEN};
 
 #[derive(Clone, Copy)]
 pub struct Dir<'a, F> {
@@ -35,27 +38,34 @@ impl <'a, F: FileSystem> Dir<'a, F> {
         DirIterator::new(self.fs, self.cluster)
     }
 
-    fn find_dir_entry(&self, name: &str) -> Result<DirEntry<'a, F>, Error> {
-        for dir_entry in self.iter() {
-            let (dir_entry, lfn) = dir_entry?;
-            dir_entry.print_name();
+    // entries without the "." and ".." ones
+    pub fn entries(&self) -> impl Iterator<Item = Result<Metadata, Error>> + 'a {
+        self.iter()
+            .filter(|entry| !matches!(entry, Ok((dir_entry, _)) if dir_entry.compare(".") || dir_entry.compare("..")))
+            .map(|entry| entry.map(|(dir_entry, lfn)| Metadata::new(&dir_entry, lfn)))
+    }
 
-            if dir_entry.compare(name) {
-                return Ok(dir_entry);
-            }
+    // entries neither hidden nor system
+    pub fn visible_entries(&self) -> impl Iterator<Item = Result<Metadata, Error>> + 'a {
+        self.entries().filter(|entry| !matches!(entry, Ok(metadata) if metadata.is_hidden() || metadata.is_system()))
+    }
 
-            if let Some(lfn) = lfn {
-                lfn.print_name();
+    fn find(&self, name: &str) -> Result<(DirEntry<'a, F>, Option<Lfn>), Error> {
+        for dir_entry in self.iter() {
+            let (dir_entry, lfn) = dir_entry?;
 
-                if lfn.compare(name) {
-                    return Ok(dir_entry);
-                }
+            if dir_entry.compare(name) || lfn.as_ref().is_some_and(|lfn| lfn.compare(name)) {
+                return Ok((dir_entry, lfn));
             }
         }
 
         Err(Error::NotFound)
     }
 
+    fn find_dir_entry(&self, name: &str) -> Result<DirEntry<'a, F>, Error> {
+        self.find(name).map(|(dir_entry, _)| dir_entry)
+    }
+
     fn follow(&self, path: &mut Path) -> Result<Dir<'a, F>, Error> {
         let mut dir = Dir {
             fs: self.fs,
@@ -71,12 +81,53 @@ impl <'a, F: FileSystem> Dir<'a, F> {
     }
 
     fn open_dir_entry(&self, path: &str) -> Result<DirEntry<'a, F>, Error> {
-        let mut path = Path::new::<F>(path)?;
+        let mut path = Path::new(path)?;
         let dir = self.follow(&mut path)?;
         dir.find_dir_entry(path.name())
     }
 
+    // short name for a new entry with its NTRes case flags, `None` if it needs a long name
+    fn short_name(&self, name: &str) -> Result<([u8; SHORT_NAME_LEN], Option<u8>), Error> {
+        let (basis, is_lossy) = short_name::basis_name(name, self.fs.code_page());
+
+        if !is_lossy {
+            return Ok((basis, short_name::case_flags(&basis, name, self.fs.code_page())));
+        }
+
+        // lowest free "~n", the directory is scanned for a window of tails at a time
+        const WINDOW: u32 = 256;
+        let mut first = 1;
+
+        while first <= short_name::MAX_TAIL {
+            let mut used = [0u32; WINDOW as usize / 32];
+
+            for dir_entry in self.iter() {
+                let (dir_entry, _) = dir_entry?;
+
+                if let Some(n) = short_name::tail_number(&basis, &dir_entry.short_name()) {
+                    if n >= first && n < first + WINDOW {
+                        used[((n - first) / 32) as usize] |= 1 << ((n - first) % 32);
+                    }
+                }
+            }
+
+            if let Some(i) = (0..WINDOW).find(|i| used[(i / 32) as usize] & (1 << (i % 32)) == 0) {
+                if first + i <= short_name::MAX_TAIL {
+                    return Ok((short_name::numeric_tail(&basis, first + i), None));
+                }
+            }
+
+            first += WINDOW;
+        }
+
+        Err(Error::ObjectAlreadyExist)
+    }
+
     fn create_dir_entry(&self, name: &str, is_file: bool, cluster: u32) -> Result<DirEntry<'a, F>, Error> {
+        if !lfn::is_valid_name(name) {
+            return Err(Error::InvalidFileName);
+        }
+
         match self.find_dir_entry(name) {
             Ok(_) => return Err(Error::ObjectAlreadyExist),
             Err(Error::NotFound) => {},
@@ -85,8 +136,13 @@ impl <'a, F: FileSystem> Dir<'a, F> {
             }
         }
 
-        let raw_dir_entry = dir_entry::create_raw(name, is_file, cluster)?;
-        let lfn_size = lfn::lfn_need_space(name);
+        let (short_name, case_flags) = self.short_name(name)?;
+        let raw_dir_entry = dir_entry::create_raw(&short_name, case_flags.unwrap_or(0), is_file, cluster, &self.fs.now())?;
+        self.write_dir_entry(name, raw_dir_entry, case_flags.is_none())
+    }
+
+    fn write_dir_entry(&self, name: &str, raw_dir_entry: [u8; DIR_ENTRY_SIZE], need_lfn: bool) -> Result<DirEntry<'a, F>, Error> {
+        let lfn_size = if need_lfn { lfn::lfn_need_space(name) } else { 0 };
         let mut pos_to_write = DirIterator::find_free_space(self.fs, self.cluster, 1 + lfn_size)?;
         
         if lfn_size != 0 {
@@ -99,26 +155,108 @@ impl <'a, F: FileSystem> Dir<'a, F> {
         Ok(dir_entry)
     }
 
+    // cluster stored in ".." of subdirectories, the root is always 0
+    fn parent_cluster(&self) -> u32 {
+        if self.cluster == self.fs.root_cluster() {
+            0
+        } else {
+            self.cluster
+        }
+    }
+
+    // whether this directory is the one at `cluster` or somewhere below it
+    fn is_inside(&self, cluster: u32) -> Result<bool, Error> {
+        let mut dir = Dir {
+            fs: self.fs,
+            cluster: self.cluster,
+        };
+
+        // a broken volume could have ".." entries going round in a loop
+        for _ in 0..self.fs.cluster_count() {
+            if dir.cluster == cluster {
+                return Ok(true);
+            }
+
+            if dir.cluster == self.fs.root_cluster() {
+                return Ok(false);
+            }
+
+            let parent = dir.find_dir_entry("..")?.cluster();
+            dir.cluster = if parent == 0 { self.fs.root_cluster() } else { parent };
+        }
+
+        Err(Error::UnexpectedClusterValue)
+    }
+
     pub fn create_dir(&self, path: &str) -> Result<Dir<'a, F>, Error> {
-        let mut path = Path::new::<F>(path)?;
+        let mut path = Path::new(path)?;
         let dir = self.follow(&mut path)?;
-        let mut stream = Stream::create(self.fs)?;
-        let raw_dir_entry = dir_entry::create_raw(".", false, stream.cluster())?;
-        stream.write(self.fs, &raw_dir_entry)?;
-        let raw_dir_entry = dir_entry::create_raw("..", false, dir.cluster)?;
-        stream.write(self.fs, &raw_dir_entry)?;
-        let dir_entry = dir.create_dir_entry(path.name(), false, stream.cluster())?;
+        let mut stream = Stream::create_dir(self.fs)?;
+        let now = self.fs.now();
+        let raw_dir_entry = dir_entry::create_raw(dir_entry::DOT_NAME, 0, false, stream.cluster(), &now)?;
+        stream.write_dir(self.fs, &raw_dir_entry)?;
+        let raw_dir_entry = dir_entry::create_raw(dir_entry::DOT_DOT_NAME, 0, false, dir.parent_cluster(), &now)?;
+        stream.write_dir(self.fs, &raw_dir_entry)?;
+        let dir_entry = dir.create_dir_entry(path.name(), false, stream.cluster())
+            .or_else(|e| Stream::remove(self.fs, stream.cluster()).and(Err(e)))?;
         dir.open(&dir_entry)
     }
 
     pub fn create_file(&self, path: &str) -> Result<File<'a, F>, Error> {
-        let mut path = Path::new::<F>(path)?;
+        let mut path = Path::new(path)?;
         let dir = self.follow(&mut path)?;
         let stream = Stream::create(self.fs)?;
-        let dir_entry = dir.create_dir_entry(path.name(), true, stream.cluster())?;
+        let dir_entry = dir.create_dir_entry(path.name(), true, stream.cluster())
+            .or_else(|e| Stream::remove(self.fs, stream.cluster()).and(Err(e)))?;
         File::open(dir_entry)
     }
 
+    pub fn metadata(&self, path: &str) -> Result<Metadata, Error> {
+        let mut path = Path::new(path)?;
+        let dir = self.follow(&mut path)?;
+        let (dir_entry, lfn) = dir.find(path.name())?;
+        Ok(Metadata::new(&dir_entry, lfn))
+    }
+
+    // the directory bit and others set by the file system itself stay as they are
+    pub fn set_attributes(&self, path: &str, attributes: Attributes) -> Result<(), Error> {
+        let mut dir_entry = self.open_dir_entry(path)?;
+        dir_entry.set_attributes(attributes.bits(), Attributes::SETTABLE.bits());
+        dir_entry.flush()
+    }
+
+    // times left as `None` stay as they are, FAT keeps the modified time in
+    // 2 s steps, the created one in 10 ms steps and the accessed one as a date
+    pub fn set_times(&self, path: &str, created: Option<DateTime>, modified: Option<DateTime>, accessed: Option<DateTime>) -> Result<(), Error> {
+        if [created, modified, accessed].iter().flatten().any(|dt| !dt.is_representable()) {
+            return Err(Error::InvalidDateTime);
+        }
+
+        let mut dir_entry = self.open_dir_entry(path)?;
+
+        if let Some(created) = created {
+            dir_entry.set_created(&created);
+        }
+
+        if let Some(modified) = modified {
+            dir_entry.set_modified(&modified);
+        }
+
+        if let Some(accessed) = accessed {
+            dir_entry.set_accessed(&accessed);
+        }
+
+        dir_entry.flush()
+    }
+
+    pub fn exists(&self, path: &str) -> Result<bool, Error> {
+        match self.open_dir_entry(path) {
+            Ok(_) => Ok(true),
+            Err(Error::NotFound) | Err(Error::NotDir) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn open_file(&self, path: &str) -> Result<File<'a, F>, Error> {
         File::open(self.open_dir_entry(path)?)
     }
@@ -151,10 +289,86 @@ impl <'a, F: FileSystem> Dir<'a, F> {
             return Err(Error::NotFile);
         }
 
+        if dir_entry.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
         Stream::remove(self.fs, dir_entry.cluster())?;
         dir_entry.remove()
     }
 
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.move_dir_entry(from, to, false)
+    }
+
+    // same as `rename`, but a file already at `to` is removed first
+    pub fn replace(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.move_dir_entry(from, to, true)
+    }
+
+    fn move_dir_entry(&self, from: &str, to: &str, replace: bool) -> Result<(), Error> {
+        let mut from_path = Path::new(from)?;
+        let from_dir = self.follow(&mut from_path)?;
+
+        if !lfn::is_valid_name(from_path.name()) {
+            return Err(Error::InvalidFileName);
+        }
+
+        let dir_entry = from_dir.find_dir_entry(from_path.name())?;
+        let mut to_path = Path::new(to)?;
+        let to_dir = self.follow(&mut to_path)?;
+        let name = to_path.name();
+
+        if !lfn::is_valid_name(name) {
+            return Err(Error::InvalidFileName);
+        }
+
+        if dir_entry.is_dir() && to_dir.is_inside(dir_entry.cluster())? {
+            return Err(Error::InvalidMove);
+        }
+
+        let existing = match to_dir.find_dir_entry(name) {
+            // only the case of the name changes
+            Ok(existing) if existing.is_same(&dir_entry) => None,
+            Ok(existing) if replace && existing.is_file() && dir_entry.is_file() => {
+                if existing.is_read_only() {
+                    return Err(Error::ReadOnly);
+                }
+
+                Some(existing)
+            },
+            Ok(_) => return Err(Error::ObjectAlreadyExist),
+            Err(Error::NotFound) => None,
+            Err(e) => {
+                return Err(e);
+            }
+        };
+
+        if let Some(mut existing) = existing {
+            // a single write of the target entry switches it over, there is
+            // always a file at `to` even if the power goes in between
+            let cluster = existing.cluster();
+            existing.take_contents(&dir_entry);
+            existing.flush()?;
+            dir_entry.remove()?;
+            return Stream::remove(self.fs, cluster);
+        }
+
+        // the new entries are written before the old ones go, a crash leaves both behind
+        let (short_name, case_flags) = to_dir.short_name(name)?;
+        let mut raw_dir_entry = dir_entry.raw();
+        dir_entry::set_short_name(&mut raw_dir_entry, &short_name, case_flags.unwrap_or(0));
+        to_dir.write_dir_entry(name, raw_dir_entry, case_flags.is_none())?;
+
+        if dir_entry.is_dir() && from_dir.cluster != to_dir.cluster {
+            let mut dot_dot = self.open(&dir_entry)?.find_dir_entry("..")?;
+            dot_dot.set_cluster(to_dir.parent_cluster());
+            dot_dot.flush()?;
+        }
+
+        dir_entry.remove()
+    }
+
     pub fn item_count(&self) -> Result<usize, Error> {
         let mut count = 0;
 
@@ -1,5 +1,9 @@
 use super::{FileSystem, Error};
+use super::code_page::OemCodePage;
 use super::stream::Stream;
+use super::short_name::SHORT_NAME_LEN;
+use super::lfn;
+use super::time::DateTime;
 
 pub const DIR_ENTRY_SIZE: usize = 32;
 
@@ -10,7 +14,13 @@ pub const ATTR_VOLUME_ID: u8 = 0x08;
 pub const ATTR_DIRECTORY: u8 = 0x10;
 pub const ATTR_ARCHIVE: u8 = 0x20;
 
+// NTRes flags, the short name is shown with a lower case name or extension
+pub const NT_RES_LOWER_BASE: u8 = 0x08;
+pub const NT_RES_LOWER_EXT: u8 = 0x10;
+
 pub const REMOVED_ENTRY: u8 = 0xE5;
+// stands for a first byte of 0xE5, which would mark the entry as removed
+const ESCAPED_E5: u8 = 0x05;
 pub const FREE_ENTRY: u8 = 0x00;
 
 pub struct DirEntry<'a, F> {
@@ -20,81 +30,51 @@ pub struct DirEntry<'a, F> {
     lfn_pos: Option<Stream>, // lfn location, if exist
 }
 
-pub fn create_raw(name: &str, is_file: bool, cluster: u32) -> Result<[u8; DIR_ENTRY_SIZE], Error> {
+pub const SHORT_NAME_BUF_SIZE: usize = 34;
+
+pub const DOT_NAME: &[u8; SHORT_NAME_LEN] = b".          ";
+pub const DOT_DOT_NAME: &[u8; SHORT_NAME_LEN] = b"..         ";
+
+pub fn create_raw(short_name: &[u8; SHORT_NAME_LEN], case_flags: u8, is_file: bool, cluster: u32, now: &DateTime) -> Result<[u8; DIR_ENTRY_SIZE], Error> {
+    let (date, time, tenths) = now.to_raw();
+    let [date_l, date_h] = date.to_le_bytes();
+    let [time_l, time_h] = time.to_le_bytes();
+
     let mut raw = [
        0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, // name 
        0x00, // attr
        0x00, // nt res
-       0x00, // create time tenth
-       0xB6, 0x3C, // create time
-       0x4D, 0x46, // create date
-       0x4D, 0x46, // acc date 
+       tenths, // create time tenth
+       time_l, time_h, // create time
+       date_l, date_h, // create date
+       date_l, date_h, // acc date 
        (cluster >> 16) as u8, (cluster >> 24) as u8, // clust high
-       0xB6, 0x3C, // write time
-       0x4D, 0x46, // write date
+       time_l, time_h, // write time
+       date_l, date_h, // write date
        cluster as u8, (cluster >> 8) as u8, // clust low
        0x00, 0x00, 0x00, 0x00, // file size
    ];
 
-   if !is_file {
-       raw[11] = ATTR_DIRECTORY;
-   }
+   set_short_name(&mut raw, short_name, case_flags);
 
    if is_file {
-       let (name, ext) = split_name_end_ext(name);
-       // copy name
-       let mut i = 0;
-       
-       for c in name.chars() {
-            if c as u8 >= b'a' && c as u8 <= b'z' {
-                raw[i] = c as u8 - b'a' + b'A';
-            } else {
-                raw[i] = c as u8;
-            }
-           i += 1;
-
-           if i == 8 {
-               break;
-           }
-       }
-       // copy extension
-       if let Some(ext) = ext {
-           let mut i = 0;
-       
-           for c in ext.chars() {
-            if c as u8 >= b'a' && c as u8 <= b'z' {
-                raw[8 + i] = c as u8 - b'a' + b'A';
-            } else {
-                raw[8 + i] = c as u8;
-            }
-               i += 1;
-
-               if i == 3 {
-                   break;
-               }
-           }
-       }
-    } else {
-        let mut i = 0;
-       
-        for c in name.chars() {
-            if c as u8 >= b'a' && c as u8 <= b'z' {
-                raw[i] = c as u8 - b'a' + b'A';
-            } else {
-                raw[i] = c as u8;
-            }
-
-            i += 1;
-
-           if i == 8 {
-               break;
-           }
-       }
+       raw[11] = ATTR_ARCHIVE;
+   } else {
+       raw[11] = ATTR_DIRECTORY;
    }
 
    Ok(raw)
 }
 
+pub fn set_short_name(raw: &mut [u8; DIR_ENTRY_SIZE], short_name: &[u8; SHORT_NAME_LEN], case_flags: u8) {
+    raw[..SHORT_NAME_LEN].copy_from_slice(short_name);
+    raw[12] = case_flags;
+
+    if raw[0] == REMOVED_ENTRY {
+        raw[0] = ESCAPED_E5;
+    }
+}
+
 impl <'a, F: FileSystem> DirEntry<'a, F> {
     pub fn from_raw_data(data: [u8; DIR_ENTRY_SIZE], fs: &'a F, stream: Stream, lfn: Option<(Stream, u8)>) -> Result<Self, Error> {
         let mut lfn_pos = None;
@@ -121,6 +101,36 @@ impl <'a, F: FileSystem> DirEntry<'a, F> {
         (self.raw[11] & (ATTR_VOLUME_ID | ATTR_DIRECTORY)) == ATTR_DIRECTORY
     }
 
+    pub fn attributes(&self) -> u8 {
+        self.raw[11]
+    }
+
+    // only the bits in `mask` change
+    pub fn set_attributes(&mut self, attributes: u8, mask: u8) {
+        self.raw[11] = (self.raw[11] & !mask) | (attributes & mask);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.raw[11] & ATTR_READ_ONLY != 0
+    }
+
+    pub fn created(&self) -> DateTime {
+        DateTime::from_raw(self.read_u16(16), self.read_u16(14), self.raw[13])
+    }
+
+    pub fn modified(&self) -> DateTime {
+        DateTime::from_raw(self.read_u16(24), self.read_u16(22), 0)
+    }
+
+    // only the date is kept, the time is midnight
+    pub fn accessed(&self) -> DateTime {
+        DateTime::from_raw(self.read_u16(18), 0, 0)
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.raw[offset], self.raw[offset + 1]])
+    }
+
     pub fn size(&self) -> u32 {
         u32::from_le_bytes([self.raw[28], self.raw[29], self.raw[30], self.raw[31]])
     }
@@ -132,74 +142,119 @@ impl <'a, F: FileSystem> DirEntry<'a, F> {
         self.raw[31] = (size >> 24) as u8;
     }
 
+    pub fn short_name(&self) -> [u8; SHORT_NAME_LEN] {
+        let mut name = [0u8; SHORT_NAME_LEN];
+        name.copy_from_slice(&self.raw[..SHORT_NAME_LEN]);
+
+        if name[0] == ESCAPED_E5 {
+            name[0] = REMOVED_ENTRY;
+        }
+
+        name
+    }
+
     pub fn cluster(&self) -> u32 {
         let cluster_h = u16::from_le_bytes([self.raw[20], self.raw[21]]);
         let cluster_l = u16::from_le_bytes([self.raw[26], self.raw[27]]);
         ((cluster_h as u32) << 16) | (cluster_l as u32)
     }
 
+    pub fn set_cluster(&mut self, cluster: u32) {
+        self.raw[20] = (cluster >> 16) as u8;
+        self.raw[21] = (cluster >> 24) as u8;
+        self.raw[26] = cluster as u8;
+        self.raw[27] = (cluster >> 8) as u8;
+    }
+
+    pub fn set_created(&mut self, created: &DateTime) {
+        let (date, time, tenths) = created.to_raw();
+        self.raw[13] = tenths;
+        self.raw[14..16].copy_from_slice(&time.to_le_bytes());
+        self.raw[16..18].copy_from_slice(&date.to_le_bytes());
+    }
+
+    pub fn set_modified(&mut self, modified: &DateTime) {
+        let (date, time, _) = modified.to_raw();
+        self.raw[22..24].copy_from_slice(&time.to_le_bytes());
+        self.raw[24..26].copy_from_slice(&date.to_le_bytes());
+    }
+
+    // `true` if the date changed, it has no time part
+    pub fn set_accessed(&mut self, accessed: &DateTime) -> bool {
+        let (date, _, _) = accessed.to_raw();
+
+        if self.raw[18..20] == date.to_le_bytes() {
+            return false;
+        }
+
+        self.raw[18..20].copy_from_slice(&date.to_le_bytes());
+        true
+    }
+
+    // everything but the name comes from `other`
+    pub fn take_contents(&mut self, other: &DirEntry<'a, F>) {
+        let mut raw = other.raw;
+        raw[..SHORT_NAME_LEN].copy_from_slice(&self.raw[..SHORT_NAME_LEN]);
+        raw[12] = self.raw[12];
+        self.raw = raw;
+    }
+
+    pub fn raw(&self) -> [u8; DIR_ENTRY_SIZE] {
+        self.raw
+    }
+
+    // both read from the same place on the volume
+    pub fn is_same(&self, other: &DirEntry<'a, F>) -> bool {
+        self.stream.is_equal(&other.stream)
+    }
+
     pub fn flush(&self) -> Result<(), Error> {
         let mut stream = self.stream;
-        stream.write(self.fs, &self.raw)?;
+        stream.write_dir(self.fs, &self.raw)?;
         Ok(())
     }
 
+    // short names are matched ignoring case like long names
     pub fn compare(&self, name: &str) -> bool {
         let (buf, len) = self.name();
-        
-        if name.len() != len {
-            return false;
-        }
-
-        for (i, &c) in name.as_bytes().into_iter().enumerate() {
-            if buf[i] != c {
-                return false;
-            }
-        }
-
-        return true;
+        lfn::eq_ignore_case(core::str::from_utf8(&buf[..len]).unwrap_or(""), name)
     }
 
-    fn name(&self) -> ([u8; 12], usize) {
-        let mut buf = [0u8; 12];
+    // decoded with the code page of the file system, a char takes at most 3 bytes
+    pub fn name(&self) -> ([u8; SHORT_NAME_BUF_SIZE], usize) {
+        let raw = self.short_name();
+        let code_page = self.fs.code_page();
+        let mut buf = [0u8; SHORT_NAME_BUF_SIZE];
         let mut len = 0;
 
-        for &c in &self.raw[..8] {
+        for &c in &raw[..8] {
             if c == b' ' {
                 break;
             }
 
-            buf[len] = c;
-            len += 1;
+            let c = code_page.decode(c);
+            let c = if self.raw[12] & NT_RES_LOWER_BASE != 0 { c.to_ascii_lowercase() } else { c };
+            len += c.encode_utf8(&mut buf[len..]).len();
         }
 
-        if self.is_file() && self.raw[8] != b' ' {
+        if raw[8] != b' ' {
             buf[len] = b'.';
             len += 1;
         }
 
-        for &c in &self.raw[8..11] {
+        for &c in &raw[8..11] {
             if c == b' ' {
                 break;
             }
 
-            buf[len] = c;
-            len += 1;
+            let c = code_page.decode(c);
+            let c = if self.raw[12] & NT_RES_LOWER_EXT != 0 { c.to_ascii_lowercase() } else { c };
+            len += c.encode_utf8(&mut buf[len..]).len();
         }
 
         (buf, len)
     }
 
-    pub fn print_name(&self) {
-        let (buf, len) = self.name();
-
-        for &c in &buf[..len] {
-            print!("{}", c as char);
-        }
-
-        println!();
-    }
-
     pub fn remove(mut self) -> Result<(), Error> {
         if let Some(mut lfn_pos) = self.lfn_pos {
             // remove LFN
@@ -207,7 +262,7 @@ impl <'a, F: FileSystem> DirEntry<'a, F> {
             buf[0] = REMOVED_ENTRY;
 
             while !lfn_pos.is_equal(&self.stream) {
-                lfn_pos.write(self.fs, &buf)?;
+                lfn_pos.write_dir(self.fs, &buf)?;
             }
         }
 
@@ -223,24 +278,14 @@ impl <'a, F: FileSystem> DirEntry<'a, F> {
 pub fn checksum(buf: &[u8]) -> u8 {
     let mut checksum = 0;
 
-    for i in 0..11 {
+    for &c in &buf[..11] {
         // NOTE: The operation is an unsigned char rotate right
         if checksum & 1 != 0 {
-            checksum = buf[i].wrapping_add(0x80 + (checksum >> 1));
+            checksum = c.wrapping_add(0x80 + (checksum >> 1));
         } else {
-            checksum = buf[i].wrapping_add(checksum >> 1);
+            checksum = c.wrapping_add(checksum >> 1);
         }
     }
     
     checksum
 }
-
-fn split_name_end_ext(name: &str) -> (&str, Option<&str>) {
-    for (i, c) in name.chars().rev().enumerate() {
-        if c == '.' {
-            return (&name[..name.len() - i - 1], Some(&name[(name.len() - i)..]));
-        }
-    }
-
-    return (name, None);
-}
@@ -1,6 +1,6 @@
 use super::{FileSystem, Error};
 use super::stream::Stream;
-use super::dir_entry::{DirEntry, DIR_ENTRY_SIZE, REMOVED_ENTRY,FREE_ENTRY};
+use super::dir_entry::{checksum, DirEntry, DIR_ENTRY_SIZE, REMOVED_ENTRY,FREE_ENTRY};
 
 use super::lfn::{Lfn, LfnBuilder};
 
@@ -13,11 +13,20 @@ pub struct DirIterator<'a, F> {
 
 impl <'a, F> DirIterator<'a, F> {
     pub fn new(fs: &'a F, cluster: u32) -> Self {
+        Self::resume(fs, Stream::open(cluster))
+    }
+
+    // continues from a `position` of an earlier iterator
+    pub fn resume(fs: &'a F, stream: Stream) -> Self {
         Self {
-            stream: Stream::open(cluster),
+            stream,
             fs,
         }
     }
+
+    pub fn position(&self) -> Stream {
+        self.stream
+    }
 }
 
 impl <'a, F: FileSystem> Iterator for DirIterator<'a, F> {
@@ -54,14 +63,16 @@ impl <'a, F: FileSystem> Iterator for DirIterator<'a, F> {
                 continue;
             }
 
-            if lfn_builder.process(pos.clone(), &buf) {
+            if lfn_builder.process(pos, &buf) {
                 continue;
             }
 
             if let Some((lfn_pos, lfn_crc)) = lfn_builder.build() {
                 if let Ok(entry) = DirEntry::from_raw_data(buf, self.fs, pos, Some((lfn_pos, lfn_crc))) {
                     if entry.is_dir() || entry.is_file() {
-                        return Some(Ok((entry, Some(lfn))));
+                        // left over from an entry that has been replaced
+                        let lfn = if checksum(&buf) == lfn_crc { Some(lfn) } else { None };
+                        return Some(Ok((entry, lfn)));
                     }
                 }
             } else {
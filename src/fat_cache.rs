@@ -0,0 +1,330 @@
+use core::cell::{Cell, RefCell};
+use super::block_device::BlockDevice;
+use super::Error;
+use super::cache::{find_slot, Slot};
+
+const BLOCK_SIZE: usize = 512;
+const BLOCKS_COUNT: usize = 4;
+const UNMIRRORED_COUNT: usize = 16;
+
+struct Block {
+    index: u32,
+    buf: [u8; BLOCK_SIZE],
+    is_valid: bool,
+    is_dirty: bool,
+    last_use: u32,
+}
+
+impl Slot for Block {
+    fn is_valid(&self) -> bool {
+        self.is_valid
+    }
+
+    fn key(&self) -> u32 {
+        self.index
+    }
+
+    fn last_use(&self) -> u32 {
+        self.last_use
+    }
+}
+
+// blocks written to the first FAT but not to the other copies yet, past
+// `UNMIRRORED_COUNT` of them the whole range between the lowest and the
+// highest gets mirrored
+#[derive(Clone, Copy)]
+struct Unmirrored {
+    indices: [u32; UNMIRRORED_COUNT],
+    len: usize,
+    range: Option<(u32, u32)>,
+    is_overflowed: bool,
+}
+
+impl Unmirrored {
+    const fn new() -> Self {
+        Self {
+            indices: [0u32; UNMIRRORED_COUNT],
+            len: 0,
+            range: None,
+            is_overflowed: false,
+        }
+    }
+
+    fn insert(&mut self, index: u32) {
+        self.range = Some(match self.range {
+            Some((first, last)) => (core::cmp::min(first, index), core::cmp::max(last, index)),
+            None => (index, index),
+        });
+
+        if self.indices[..self.len].contains(&index) {
+            return;
+        }
+
+        if self.len == UNMIRRORED_COUNT {
+            self.is_overflowed = true;
+        } else {
+            self.indices[self.len] = index;
+            self.len += 1;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.range.is_none()
+    }
+
+    // the listed blocks, or every one in the range once the list overflowed
+    fn get(&self, n: u32) -> Option<u32> {
+        match self.range {
+            Some((first, last)) if self.is_overflowed => Some(first + n).filter(|&index| index <= last),
+            Some(_) => self.indices[..self.len].get(n as usize).copied(),
+            None => None,
+        }
+    }
+}
+
+// Caches the FAT in 512 byte blocks. Changes only go to the first FAT, the
+// other copies are brought up to date on flush once the first FAT has been
+// written and flushed, so a crash always leaves one consistent copy behind.
+pub struct FatCache {
+    first_fat_table_sector: u32,
+    sector_size: u32,
+    fat_size_in_sectors: u32,
+    fats_count: u32,
+    blocks: RefCell<[Block; BLOCKS_COUNT]>,
+    tick: Cell<u32>,
+    unmirrored: Cell<Unmirrored>,
+}
+
+impl FatCache {
+    pub fn new(first_fat_table_sector: u32, sector_size: u32, fat_size_in_sectors: u32, fats_count: u32) -> Self {
+        Self {
+            first_fat_table_sector,
+            sector_size,
+            fat_size_in_sectors,
+            fats_count,
+            blocks: RefCell::new(core::array::from_fn(|_| Block {
+                index: 0,
+                buf: [0u8; BLOCK_SIZE],
+                is_valid: false,
+                is_dirty: false,
+                last_use: 0,
+            })),
+            tick: Cell::new(0),
+            unmirrored: Cell::new(Unmirrored::new()),
+        }
+    }
+
+    pub fn read<D: BlockDevice>(&self, dev: &D, offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+        let mut blocks = self.blocks.borrow_mut();
+        let mut bytes_read = 0;
+
+        while bytes_read != buf.len() {
+            let pos = offset as usize + bytes_read;
+            let block_offset = pos % BLOCK_SIZE;
+            let len = core::cmp::min(buf.len() - bytes_read, BLOCK_SIZE - block_offset);
+            let i = self.block(dev, &mut blocks, (pos / BLOCK_SIZE) as u32)?;
+            buf[bytes_read..bytes_read + len].copy_from_slice(&blocks[i].buf[block_offset..block_offset + len]);
+            bytes_read += len;
+        }
+
+        Ok(())
+    }
+
+    pub fn write<D: BlockDevice>(&self, dev: &D, offset: u32, buf: &[u8]) -> Result<(), Error> {
+        let mut blocks = self.blocks.borrow_mut();
+        let mut bytes_written = 0;
+
+        while bytes_written != buf.len() {
+            let pos = offset as usize + bytes_written;
+            let block_offset = pos % BLOCK_SIZE;
+            let len = core::cmp::min(buf.len() - bytes_written, BLOCK_SIZE - block_offset);
+            let i = self.block(dev, &mut blocks, (pos / BLOCK_SIZE) as u32)?;
+            blocks[i].buf[block_offset..block_offset + len].copy_from_slice(&buf[bytes_written..bytes_written + len]);
+            blocks[i].is_dirty = true;
+            bytes_written += len;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush<D: BlockDevice>(&self, dev: &D) -> Result<(), Error> {
+        let mut blocks = self.blocks.borrow_mut();
+
+        for block in blocks.iter_mut() {
+            if block.is_valid && block.is_dirty {
+                self.write_block(dev, block)?;
+            }
+        }
+
+        let unmirrored = self.unmirrored.get();
+
+        if unmirrored.is_empty() {
+            return Ok(());
+        }
+
+        // the first FAT has to be on the media before the copies are touched
+        dev.flush().or(Err(Error::DeviceIO))?;
+
+        let mut i = 0;
+
+        while let Some(index) = unmirrored.get(i) {
+            i += 1;
+            let mut buf = [0u8; BLOCK_SIZE];
+
+            match blocks.iter().find(|b| b.is_valid && b.index == index) {
+                Some(block) => buf.copy_from_slice(&block.buf),
+                None => {
+                    let (sector, offset) = self.location(index);
+                    dev.read(sector, offset, &mut buf).or(Err(Error::DeviceIO))?;
+                },
+            }
+
+            for n in 1..self.fats_count {
+                let (sector, offset) = self.location(index);
+                dev.write(sector + n * self.fat_size_in_sectors, offset, &buf).or(Err(Error::DeviceIO))?;
+            }
+        }
+
+        self.unmirrored.set(Unmirrored::new());
+        Ok(())
+    }
+
+    pub fn fats_count(&self) -> u32 {
+        self.fats_count
+    }
+
+    // first sector of FAT `copy` that differs from the first FAT on the media
+    pub fn copy_mismatch<D: BlockDevice>(&self, dev: &D, copy: u32) -> Result<Option<u32>, Error> {
+        let blocks_count = self.fat_size_in_sectors * self.sector_size / BLOCK_SIZE as u32;
+
+        for index in 0..blocks_count {
+            let mut first = [0u8; BLOCK_SIZE];
+            let mut other = [0u8; BLOCK_SIZE];
+            let (sector, offset) = self.location(index);
+            dev.read(sector, offset, &mut first).or(Err(Error::DeviceIO))?;
+            dev.read(sector + copy * self.fat_size_in_sectors, offset, &mut other).or(Err(Error::DeviceIO))?;
+
+            if first != other {
+                return Ok(Some(sector - self.first_fat_table_sector));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn location(&self, index: u32) -> (u32, usize) {
+        let pos = index * BLOCK_SIZE as u32;
+        (self.first_fat_table_sector + pos / self.sector_size, (pos % self.sector_size) as usize)
+    }
+
+    fn write_block<D: BlockDevice>(&self, dev: &D, block: &mut Block) -> Result<(), Error> {
+        let (sector, offset) = self.location(block.index);
+        dev.write(sector, offset, &block.buf).or(Err(Error::DeviceIO))?;
+        block.is_dirty = false;
+
+        if self.fats_count > 1 {
+            let mut unmirrored = self.unmirrored.get();
+            unmirrored.insert(block.index);
+            self.unmirrored.set(unmirrored);
+        }
+
+        Ok(())
+    }
+
+    fn block<D: BlockDevice>(&self, dev: &D, blocks: &mut [Block; BLOCKS_COUNT], index: u32) -> Result<usize, Error> {
+        let tick = self.tick.get().wrapping_add(1);
+        self.tick.set(tick);
+
+        let i = match find_slot(blocks, index, tick) {
+            Ok(i) => {
+                blocks[i].last_use = tick;
+                return Ok(i);
+            },
+            Err(i) => i,
+        };
+
+        let block = &mut blocks[i];
+
+        if block.is_valid && block.is_dirty {
+            self.write_block(dev, block)?;
+        }
+
+        block.is_valid = false;
+        let (sector, offset) = self.location(index);
+        dev.read(sector, offset, &mut block.buf).or(Err(Error::DeviceIO))?;
+        block.index = index;
+        block.is_valid = true;
+        block.last_use = tick;
+        Ok(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_device::RamDevice;
+
+    const FAT_SIZE: u32 = 128;
+
+    fn cache(fats_count: u32) -> (RamDevice, FatCache) {
+        (RamDevice::new(1 + FAT_SIZE * 2, 512), FatCache::new(1, 512, FAT_SIZE, fats_count))
+    }
+
+    #[test]
+    fn mirror_dirty_blocks_only() {
+        let (dev, fat) = cache(2);
+        fat.write(&dev, 12, &[1, 2, 3, 4]).unwrap();
+        fat.write(&dev, (FAT_SIZE - 1) * 512 + 8, &[5, 6, 7, 8]).unwrap();
+
+        let (reads, writes) = (dev.reads(), dev.writes());
+        fat.flush(&dev).unwrap();
+        assert_eq!(dev.reads(), reads);
+        assert_eq!(dev.writes() - writes, 4);
+        assert_eq!(fat.copy_mismatch(&dev, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn mirror_evicted_blocks() {
+        let (dev, fat) = cache(2);
+
+        for index in 0..BLOCKS_COUNT as u32 + 2 {
+            fat.write(&dev, index * 512 * 3, &[index as u8 + 1]).unwrap();
+        }
+
+        assert!(fat.copy_mismatch(&dev, 1).unwrap().is_some());
+
+        let writes = dev.writes();
+        fat.flush(&dev).unwrap();
+        assert_eq!(dev.writes() - writes, BLOCKS_COUNT + BLOCKS_COUNT + 2);
+        assert_eq!(fat.copy_mismatch(&dev, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn mirror_range_on_overflow() {
+        let (dev, fat) = cache(2);
+
+        for index in 0..UNMIRRORED_COUNT as u32 + 1 {
+            fat.write(&dev, index * 512 * 2, &[0xA5]).unwrap();
+        }
+
+        fat.flush(&dev).unwrap();
+        assert_eq!(fat.copy_mismatch(&dev, 1).unwrap(), None);
+
+        // nothing left to mirror
+        let writes = dev.writes();
+        fat.flush(&dev).unwrap();
+        assert_eq!(dev.writes(), writes);
+    }
+
+    #[test]
+    fn single_fat() {
+        let (dev, fat) = cache(1);
+        fat.write(&dev, 12, &[1, 2, 3, 4]).unwrap();
+        fat.flush(&dev).unwrap();
+
+        let mut buf = [0u8; 4];
+        dev.read(1 + FAT_SIZE, 12, &mut buf).unwrap();
+        assert_eq!(buf, [0u8; 4]);
+        assert_eq!(dev.writes(), 1);
+    }
+}
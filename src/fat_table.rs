@@ -1,16 +1,30 @@
 use super::{FileSystem, Error, ClusterValue};
 
 pub fn create<F: FileSystem>(fs: &F) -> Result<u32, Error> {
-    for cluster in 2..fs.cluster_count() {
-        if let ClusterValue::Free = fs.fat_table_get(cluster)? {
-            fs.fat_table_set(cluster, ClusterValue::Last)?;
-            let mut offset = 0;
+    let cluster = find_free(fs, fs.next_free())?;
+    allocate(fs, cluster)
+}
 
-            while offset != fs.cluster_size() {
-                let zero_data = [0u8; 32];
-                fs.write(cluster, offset, &zero_data)?;
-                offset += zero_data.len();
-            }
+pub fn extend<F: FileSystem>(fs: &F, cluster: u32) -> Result<u32, Error> {
+    // keep the chain contiguous when the following cluster is free
+    let next_cluster = cluster + 1;
+    let new_cluster = if next_cluster < fs.cluster_count() + 2 && matches!(fs.fat_table_get(next_cluster)?, ClusterValue::Free) {
+        allocate(fs, next_cluster)?
+    } else {
+        create(fs)?
+    };
+
+    fs.fat_table_set(cluster, ClusterValue::Next(new_cluster))?;
+    Ok(new_cluster)
+}
+
+fn find_free<F: FileSystem>(fs: &F, start: u32) -> Result<u32, Error> {
+    let end = fs.cluster_count() + 2;
+    let start = if start < 2 || start >= end { 2 } else { start };
+
+    // search from the hint to the end, then wrap around
+    for cluster in (start..end).chain(2..start) {
+        if let ClusterValue::Free = fs.fat_table_get(cluster)? {
             return Ok(cluster);
         }
     }
@@ -18,10 +32,23 @@ pub fn create<F: FileSystem>(fs: &F) -> Result<u32, Error> {
     Err(Error::NoFreeCluster)
 }
 
-pub fn extend<F: FileSystem>(fs: &F, cluster: u32) -> Result<u32, Error> {
-    let new_cluster = create(fs)?;
-    fs.fat_table_set(cluster, ClusterValue::Next(new_cluster))?;
-    Ok(new_cluster)
+fn allocate<F: FileSystem>(fs: &F, cluster: u32) -> Result<u32, Error> {
+    fs.fat_table_set(cluster, ClusterValue::Last)?;
+    fs.cluster_allocated(cluster);
+    Ok(cluster)
+}
+
+// only directories need it, they end at the first free entry while file data
+// past the size is never read
+pub fn zero<F: FileSystem>(fs: &F, cluster: u32) -> Result<(), Error> {
+    let zero_data = [0u8; 512];
+    let mut offset = 0;
+
+    while offset != fs.cluster_size() {
+        offset += fs.write(cluster, offset, &zero_data)?;
+    }
+
+    Ok(())
 }
 
 pub fn remove<F: FileSystem>(fs: &F, cluster: u32) -> Result<(), Error> {
@@ -31,10 +58,12 @@ pub fn remove<F: FileSystem>(fs: &F, cluster: u32) -> Result<(), Error> {
         match fs.fat_table_get(cluster)? {
             ClusterValue::Next(next_cluster) => {
                 fs.fat_table_set(cluster, ClusterValue::Free)?;
+                fs.cluster_freed(cluster);
                 cluster = next_cluster;
             },
             ClusterValue::Last => {
                 fs.fat_table_set(cluster, ClusterValue::Free)?;
+                fs.cluster_freed(cluster);
                 return Ok(());
             },
             ClusterValue::Free | ClusterValue::Bad => {
@@ -56,6 +85,7 @@ pub fn truncate<F: FileSystem>(fs: &F, cluster: u32) -> Result<(), Error> {
                     fs.fat_table_set(cluster, ClusterValue::Last)?;
                 } else {
                     fs.fat_table_set(cluster, ClusterValue::Free)?;
+                    fs.cluster_freed(cluster);
                 }
                 
                 cluster = next_cluster;
@@ -63,6 +93,7 @@ pub fn truncate<F: FileSystem>(fs: &F, cluster: u32) -> Result<(), Error> {
             ClusterValue::Last => {
                 if !first {
                     fs.fat_table_set(cluster, ClusterValue::Free)?;
+                    fs.cluster_freed(cluster);
                 }
                 return Ok(());
             },
@@ -72,3 +103,68 @@ pub fn truncate<F: FileSystem>(fs: &F, cluster: u32) -> Result<(), Error> {
         }          
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::block_device::{BlockDevice, RamDevice};
+    use crate::dir::Dir;
+    use crate::format::FormatOptions;
+    use crate::fs::Fs;
+    use crate::FatType;
+
+    // left over data everywhere, format only clears the system area
+    fn dirty_fs(fat_type: FatType, sectors_count: u32) -> Fs<RamDevice> {
+        let dev = RamDevice::new(sectors_count, 512);
+
+        for sector in 0..sectors_count {
+            dev.write(sector, 0, &[0xA5u8; 512]).unwrap();
+        }
+
+        Fs::format_with(&dev, &FormatOptions::new().fat_type(fat_type).sectors_in_cluster(1)).unwrap();
+        Fs::mount(dev).unwrap()
+    }
+
+    #[test]
+    fn grown_dirs_are_zeroed() {
+        for (fat_type, sectors_count) in [(FatType::Fat16, 40000), (FatType::Fat32, 70000)] {
+            let fs = dirty_fs(fat_type, sectors_count);
+            let root = Dir::root(&fs).unwrap();
+            let dir = root.create_dir("a directory").unwrap();
+            assert_eq!(dir.item_count().unwrap(), 0);
+
+            // 3 entries each, a 512 byte cluster takes 5 of them
+            for n in 0..20 {
+                let mut name = *b"a long file name 00";
+                name[17] += n / 10;
+                name[18] += n % 10;
+                dir.create_file(core::str::from_utf8(&name).unwrap()).unwrap();
+            }
+
+            assert_eq!(dir.item_count().unwrap(), 20);
+            assert!(dir.exists("a long file name 19").unwrap());
+        }
+    }
+
+    #[test]
+    fn file_clusters_are_not_zeroed() {
+        let fs = dirty_fs(FatType::Fat16, 40000);
+        let root = Dir::root(&fs).unwrap();
+        root.create_file("data").unwrap().close().unwrap();
+
+        let dev = fs.unmount().unwrap();
+        let writes = dev.writes();
+        let fs = Fs::mount(dev).unwrap();
+        let root = Dir::root(&fs).unwrap();
+        let mut file = root.open_file("data").unwrap();
+
+        for _ in 0..64 {
+            file.write(&[0x5Au8; 512]).unwrap();
+        }
+
+        file.close().unwrap();
+
+        // a write for each sector of data, the rest are the FAT, its copy and the entry
+        let dev = fs.unmount().unwrap();
+        assert!(dev.writes() - writes < 64 + 16);
+    }
+}
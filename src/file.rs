@@ -1,6 +1,6 @@
 use super::{FileSystem, Error};
 use super::stream::Stream;
-use super::dir_entry::DirEntry;
+use super::dir_entry::{DirEntry, ATTR_ARCHIVE};
 
 pub struct File<'a, F> {
     fs: &'a F,
@@ -8,8 +8,10 @@ pub struct File<'a, F> {
     stream: Stream,
     offset: u32,
     is_dirty: bool,
+    is_modified: bool,
 }
-enum SeekFrom {
+
+pub enum SeekFrom {
     Start(usize),
     Current(isize),
     End(isize),
@@ -28,6 +30,7 @@ impl <'a, F: FileSystem> File<'a, F> {
             dir_entry,
             offset: 0,
             is_dirty: false,
+            is_modified: false,
             fs,
         })
     }
@@ -37,80 +40,61 @@ impl <'a, F: FileSystem> File<'a, F> {
         let len_to_read = core::cmp::min(buf.len(), bytes_left_in_file as usize);
         let len = self.stream.read(self.fs, &mut buf[..len_to_read])?;
         self.offset += len as u32;
+
+        if len != 0 && self.fs.updates_access_date() {
+            self.is_dirty |= self.dir_entry.set_accessed(&self.fs.now());
+        }
+
         Ok(len)
     }
 
-    fn seek(&mut self, pos: SeekFrom) -> Result<usize, Error> {
+    // positions before the start are an error, the ones past the end stop at it
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<usize, Error> {
         let offset_from_origin = match pos {
             SeekFrom::Current(offset) => {
-                self.offset as isize + offset
+                (self.offset as i64).checked_add(offset as i64)
             },
             SeekFrom::Start(offset) => {
-                offset as isize
+                i64::try_from(offset).ok()
             },
             SeekFrom::End(offset) => {
-                self.dir_entry.size() as isize + offset
+                (self.dir_entry.size() as i64).checked_add(offset as i64)
             },
         };
 
-        let pos = {
-            if offset_from_origin < 0 {
-                0
-            } else if offset_from_origin as u32 > self.dir_entry.size() {
-                self.dir_entry.size()
-            } else {
-                offset_from_origin as u32
-            }
+        let pos = match offset_from_origin {
+            Some(offset) if offset < 0 => return Err(Error::InvalidSeek),
+            Some(offset) if offset < self.dir_entry.size() as i64 => offset as u32,
+            _ => self.dir_entry.size(),
         };
 
-        //self.stream.seek(fs, pos)
-        todo!();
-    }
-
-    /*
-    pub fn seek(&mut self, fs: &Fs, pos: usize) -> Result<usize, Error> {
-        let cluster_size = fs.sectors_in_cluster() as usize * fs.sector_size();
-        let pos_cluster = pos / cluster_size;
-        let current_pos_cluster = self.offset_from_origin / cluster_size;
-
-        let mut start_cluster;
-        let clusters_to_skip;
-
-        if pos_cluster < current_pos_cluster {
-            start_cluster = self.origin;
-            clusters_to_skip = pos_cluster;
-        } else {
-            start_cluster = self.current;
-            clusters_to_skip = current_pos_cluster - pos_cluster;
-        }
-        // skip clusters if need
-        for _ in 0..clusters_to_skip {
-            match FatTable::get(fs, start_cluster)? {
-                ClusterValue::Next(cluster) => {
-                    start_cluster = cluster;
-                },
-                ClusterValue::Last => {
-                    return Err(Error::UnexpectedEndOfFile);
-                },
-                ClusterValue::Free | ClusterValue::Bad => {
-                    return Err(Error::UnexpectedClusterValue);
-                }
-            }
+        self.stream.seek(self.fs, self.dir_entry.cluster(), self.offset, pos)?;
+        self.offset = pos;
+        Ok(pos as usize)
+    }
+
+    // empty files written by other systems have no cluster yet, 0 would be
+    // taken for the FAT12/16 root directory
+    fn allocate_first_cluster(&mut self) -> Result<(), Error> {
+        if self.dir_entry.cluster() == 0 {
+            self.stream = Stream::create(self.fs)?;
+            self.dir_entry.set_cluster(self.stream.cluster());
+            self.is_dirty = true;
         }
 
-        let start_cluster_sector = fs.cluster_to_sector(start_cluster)?;
-        self.current = start_cluster;
-        self.sector = start_cluster_sector + ((pos % cluster_size) / fs.sector_size()) as u32;
-        self.last_sector_in_cluster = start_cluster_sector + fs.sector_size() as u32;
-        self.offset_in_sector = pos / fs.sector_size();
-        self.offset_from_origin = pos;
-        Ok(self.offset_from_origin)
+        Ok(())
     }
-    */
 
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.dir_entry.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        self.allocate_first_cluster()?;
+
         let len = self.stream.write(self.fs, buf)?;
         self.offset += len as u32;
+        self.is_modified |= len != 0;
 
         if self.offset > self.dir_entry.size() {
             self.dir_entry.set_size(self.offset);
@@ -119,7 +103,50 @@ impl <'a, F: FileSystem> File<'a, F> {
         Ok(len)
     }
 
+    pub fn read_at(&self, offset: u32, buf: &mut [u8]) -> Result<usize, Error> {
+        if offset >= self.dir_entry.size() {
+            return Ok(0);
+        }
+
+        // work on a copy so the cursor stays where it is
+        let mut stream = self.stream;
+        stream.seek(self.fs, self.dir_entry.cluster(), self.offset, offset)?;
+        let len_to_read = core::cmp::min(buf.len(), (self.dir_entry.size() - offset) as usize);
+        stream.read(self.fs, &mut buf[..len_to_read])
+    }
+
+    pub fn write_at(&mut self, offset: u32, buf: &[u8]) -> Result<usize, Error> {
+        if self.dir_entry.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        if offset > self.dir_entry.size() {
+            return Err(Error::UnexpectedEndOfFile);
+        }
+
+        self.allocate_first_cluster()?;
+
+        let mut stream = self.stream;
+        stream.seek(self.fs, self.dir_entry.cluster(), self.offset, offset)?;
+        let len = stream.write(self.fs, buf)?;
+        self.is_modified |= len != 0;
+
+        if offset + len as u32 > self.dir_entry.size() {
+            self.dir_entry.set_size(offset + len as u32);
+            self.is_dirty = true;
+        }
+        Ok(len)
+    }
+
     pub fn flush(&mut self) -> Result<(), Error> {
+        if self.is_modified {
+            // changed contents also flag the file for backup
+            self.dir_entry.set_modified(&self.fs.now());
+            self.dir_entry.set_attributes(ATTR_ARCHIVE, ATTR_ARCHIVE);
+            self.is_modified = false;
+            self.is_dirty = true;
+        }
+
         if self.is_dirty {
             self.dir_entry.flush()?;
             self.is_dirty = false;
@@ -128,13 +155,277 @@ impl <'a, F: FileSystem> File<'a, F> {
     }
 
     pub fn truncate(&mut self) -> Result<u32, Error> {
-        self.stream.truncate(self.fs)?;
+        if self.dir_entry.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        if self.dir_entry.cluster() != 0 {
+            self.stream.truncate(self.fs)?;
+        }
+
         self.dir_entry.set_size(self.offset);
         self.is_dirty = true;
+        self.is_modified = true;
         Ok(self.offset)
     }
 
     pub fn close(mut self) -> Result<(), Error> {
         self.flush()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "std")]
+impl <'a, F: FileSystem> std::io::Read for File<'a, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(File::read(self, buf)?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl <'a, F: FileSystem> std::io::Write for File<'a, F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(File::write(self, buf)?)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(File::flush(self)?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl <'a, F: FileSystem> std::io::Seek for File<'a, F> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(offset) => SeekFrom::Start(usize::try_from(offset).or(Err(Error::InvalidSeek))?),
+            std::io::SeekFrom::Current(offset) => SeekFrom::Current(isize::try_from(offset).or(Err(Error::InvalidSeek))?),
+            std::io::SeekFrom::End(offset) => SeekFrom::End(isize::try_from(offset).or(Err(Error::InvalidSeek))?),
+        };
+
+        Ok(File::seek(self, pos)? as u64)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl <'a, F: FileSystem> embedded_io::ErrorType for File<'a, F> {
+    type Error = Error;
+}
+
+#[cfg(feature = "embedded-io")]
+impl <'a, F: FileSystem> embedded_io::Read for File<'a, F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        File::read(self, buf)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl <'a, F: FileSystem> embedded_io::Write for File<'a, F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        File::write(self, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        File::flush(self)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl <'a, F: FileSystem> embedded_io::Seek for File<'a, F> {
+    fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, Error> {
+        let pos = match pos {
+            embedded_io::SeekFrom::Start(offset) => SeekFrom::Start(usize::try_from(offset).or(Err(Error::InvalidSeek))?),
+            embedded_io::SeekFrom::Current(offset) => SeekFrom::Current(isize::try_from(offset).or(Err(Error::InvalidSeek))?),
+            embedded_io::SeekFrom::End(offset) => SeekFrom::End(isize::try_from(offset).or(Err(Error::InvalidSeek))?),
+        };
+
+        Ok(File::seek(self, pos)? as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_device::RamDevice;
+    use crate::dir::Dir;
+    use crate::format::FormatOptions;
+    use crate::fs::Fs;
+    use crate::{ClusterValue, FatType};
+
+    const FAT_TYPES: [(FatType, u32); 3] = [(FatType::Fat12, 2048), (FatType::Fat16, 40000), (FatType::Fat32, 70000)];
+    const SIZE: usize = 4096;
+
+    // clusters of a single sector so the chain has a few of them
+    fn fs(fat_type: FatType, sectors_count: u32) -> Fs<RamDevice> {
+        let dev = RamDevice::new(sectors_count, 512);
+        Fs::format_with(&dev, &FormatOptions::new().fat_type(fat_type).sectors_in_cluster(1)).unwrap();
+        Fs::mount(dev).unwrap()
+    }
+
+    fn byte(pos: usize) -> u8 {
+        (pos % 251) as u8
+    }
+
+    fn create<'a>(root: &Dir<'a, Fs<RamDevice>>) -> File<'a, Fs<RamDevice>> {
+        let mut file = root.create_file("file").unwrap();
+        let mut data = [0u8; SIZE];
+
+        for (i, c) in data.iter_mut().enumerate() {
+            *c = byte(i);
+        }
+
+        assert_eq!(file.write(&data).unwrap(), SIZE);
+        file.flush().unwrap();
+        file
+    }
+
+    fn read_byte(file: &mut File<Fs<RamDevice>>) -> Option<u8> {
+        let mut buf = [0u8; 1];
+        (file.read(&mut buf).unwrap() == 1).then_some(buf[0])
+    }
+
+    #[test]
+    fn seek_from_start() {
+        for (fat_type, sectors_count) in FAT_TYPES {
+            let fs = fs(fat_type, sectors_count);
+            let root = Dir::root(&fs).unwrap();
+            let mut file = create(&root);
+
+            // forward from where the last seek left off, and back from the start
+            for pos in [0, 511, 512, 513, 2047, 2048, 1024, 512, 4095, 0] {
+                assert_eq!(file.seek(SeekFrom::Start(pos)).unwrap(), pos);
+                assert_eq!(read_byte(&mut file), Some(byte(pos)));
+            }
+
+            assert_eq!(file.seek(SeekFrom::Start(SIZE)).unwrap(), SIZE);
+            assert_eq!(read_byte(&mut file), None);
+        }
+    }
+
+    #[test]
+    fn seek_from_end_and_current() {
+        for (fat_type, sectors_count) in FAT_TYPES {
+            let fs = fs(fat_type, sectors_count);
+            let root = Dir::root(&fs).unwrap();
+            let mut file = create(&root);
+
+            assert_eq!(file.seek(SeekFrom::End(-5)).unwrap(), SIZE - 5);
+            assert_eq!(read_byte(&mut file), Some(byte(SIZE - 5)));
+
+            assert_eq!(file.seek(SeekFrom::Current(-2000)).unwrap(), SIZE - 4 - 2000);
+            assert_eq!(read_byte(&mut file), Some(byte(SIZE - 4 - 2000)));
+
+            assert_eq!(file.seek(SeekFrom::Current(-(SIZE as isize - 2003))).unwrap(), 0);
+            assert!(matches!(file.seek(SeekFrom::Current(-1)), Err(Error::InvalidSeek)));
+            assert!(matches!(file.seek(SeekFrom::End(-(SIZE as isize) - 1)), Err(Error::InvalidSeek)));
+            assert_eq!(read_byte(&mut file), Some(byte(0)));
+
+            assert_eq!(file.seek(SeekFrom::Current(511)).unwrap(), 512);
+            assert_eq!(read_byte(&mut file), Some(byte(512)));
+        }
+    }
+
+    #[test]
+    fn seek_past_end() {
+        for (fat_type, sectors_count) in FAT_TYPES {
+            let fs = fs(fat_type, sectors_count);
+            let root = Dir::root(&fs).unwrap();
+            let mut file = create(&root);
+
+            assert_eq!(file.seek(SeekFrom::Start(10000)).unwrap(), SIZE);
+            assert_eq!(file.seek(SeekFrom::End(10)).unwrap(), SIZE);
+            assert_eq!(file.seek(SeekFrom::Current(isize::MAX)).unwrap(), SIZE);
+            assert_eq!(read_byte(&mut file), None);
+
+            // a write at the end goes on in a new cluster
+            assert_eq!(file.write(&[0x42]).unwrap(), 1);
+            assert_eq!(file.seek(SeekFrom::Start(SIZE)).unwrap(), SIZE);
+            assert_eq!(read_byte(&mut file), Some(0x42));
+        }
+    }
+
+    #[test]
+    fn seek_on_broken_chain() {
+        let fs = fs(FatType::Fat16, 40000);
+        let root = Dir::root(&fs).unwrap();
+        let mut file = create(&root);
+
+        // the chain ends after two clusters while the size says eight
+        let cluster = file.dir_entry.cluster();
+        let second = match fs.fat_table_get(cluster).unwrap() {
+            ClusterValue::Next(next) => next,
+            _ => panic!(),
+        };
+        fs.fat_table_set(second, ClusterValue::Last).unwrap();
+
+        assert_eq!(file.seek(SeekFrom::Start(1000)).unwrap(), 1000);
+        assert!(matches!(file.seek(SeekFrom::Start(3000)), Err(Error::UnexpectedEndOfFile)));
+    }
+
+    #[test]
+    fn positional_io_keeps_cursor() {
+        let fs = fs(FatType::Fat16, 40000);
+        let root = Dir::root(&fs).unwrap();
+        let mut file = create(&root);
+        file.seek(SeekFrom::Start(100)).unwrap();
+
+        let mut buf = [0u8; 600];
+        assert_eq!(file.read_at(3000, &mut buf).unwrap(), buf.len());
+        assert!(buf.iter().enumerate().all(|(i, &c)| c == byte(3000 + i)));
+        assert_eq!(file.read_at(SIZE as u32 - 10, &mut buf).unwrap(), 10);
+        assert_eq!(file.read_at(SIZE as u32, &mut buf).unwrap(), 0);
+
+        assert_eq!(file.write_at(1000, &[0xEE; 100]).unwrap(), 100);
+        assert_eq!(read_byte(&mut file), Some(byte(100)));
+        assert_eq!(file.read_at(999, &mut buf[..3]).unwrap(), 3);
+        assert_eq!(buf[..3], [byte(999), 0xEE, 0xEE]);
+    }
+
+    #[test]
+    fn write_at_end_grows_file() {
+        let fs = fs(FatType::Fat16, 40000);
+        let root = Dir::root(&fs).unwrap();
+        let mut file = create(&root);
+
+        assert_eq!(file.write_at(SIZE as u32, &[1, 2, 3]).unwrap(), 3);
+        file.flush().unwrap();
+        assert_eq!(root.metadata("file").unwrap().size(), SIZE as u32 + 3);
+
+        let mut buf = [0u8; 3];
+        assert_eq!(file.read_at(SIZE as u32, &mut buf).unwrap(), 3);
+        assert_eq!(buf, [1, 2, 3]);
+
+        // the cursor stayed at the old end
+        assert_eq!(read_byte(&mut file), Some(1));
+
+        assert!(matches!(file.write_at(SIZE as u32 + 4, &[4]), Err(Error::UnexpectedEndOfFile)));
+        file.flush().unwrap();
+        assert_eq!(root.metadata("file").unwrap().size(), SIZE as u32 + 3);
+    }
+
+    #[test]
+    fn write_at_allocates_first_cluster() {
+        let fs = fs(FatType::Fat16, 40000);
+        let root = Dir::root(&fs).unwrap();
+
+        // the way other systems leave empty files
+        let mut file = root.create_file("empty").unwrap();
+        Stream::remove(&fs, file.dir_entry.cluster()).unwrap();
+        file.dir_entry.set_cluster(0);
+        file.dir_entry.flush().unwrap();
+
+        let mut file = root.open_file("empty").unwrap();
+        assert_eq!(file.dir_entry.cluster(), 0);
+        assert_eq!(file.write_at(0, b"hello").unwrap(), 5);
+        file.close().unwrap();
+
+        let mut file = root.open_file("empty").unwrap();
+        assert_ne!(file.dir_entry.cluster(), 0);
+        assert_eq!(root.metadata("empty").unwrap().size(), 5);
+
+        let mut buf = [0u8; 5];
+        assert_eq!(file.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+
+        let mut used = std::vec![0u32; crate::check::bitmap_len(&fs)];
+        assert!(crate::check::check::<4, _, _, _>(&fs, &mut used).unwrap().is_clean());
+    }
+}
@@ -0,0 +1,562 @@
+use super::block_device::BlockDevice;
+use super::fs::determine_fat_type_by_clusters_count;
+use super::{Error, FatType};
+use super::dir_entry::{ATTR_VOLUME_ID, DIR_ENTRY_SIZE};
+
+const MEDIA_FIXED_DISK: u8 = 0xF8;
+const DRIVE_NUMBER: u8 = 0x80;
+const EXT_BOOT_SIGNATURE: u8 = 0x29;
+const OEM_NAME: &[u8; 8] = b"MSWIN4.1";
+const NO_NAME: &[u8; 11] = b"NO NAME    ";
+
+const FAT12_MAX_SECTORS: u32 = 8400;
+const FAT16_MAX_SECTORS: u32 = 1048576;
+
+const FAT16_ROOT_ENTRIES: u32 = 512;
+const FAT16_RESERVED_SECTORS: u32 = 1;
+const FAT32_RESERVED_SECTORS: u32 = 32;
+const FATS_COUNT: u32 = 2;
+
+const FAT32_ROOT_CLUSTER: u32 = 2;
+const FAT32_MAX_CLUSTERS: u32 = 0x0FFF_FFF4;
+const FAT32_FS_INFO_SECTOR: u16 = 1;
+const FAT32_BACKUP_BOOT_SECTOR: u16 = 6;
+
+pub const FS_INFO_LEAD_SIGNATURE: u32 = 0x41615252;
+pub const FS_INFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+pub const FS_INFO_TRAIL_SIGNATURE: u32 = 0xAA550000;
+
+// (disk size in 512 byte sectors, sectors per cluster for 512 byte sectors)
+const DISK_TABLE_FAT16: [(u32, u32); 8] = [
+    (8400, 0), /* disks up to 4.1 MB, the 0 value for SecPerClusVal trips an error */
+    (32680, 2), /* disks up to 16 MB, 1k cluster */
+    (262144, 4), /* disks up to 128 MB, 2k cluster */
+    (524288, 8), /* disks up to 256 MB, 4k cluster */
+    (1048576, 16), /* disks up to 512 MB, 8k cluster */
+    /* The entries after this point are not used unless FAT16 is forced */
+    (2097152, 32), /* disks up to 1 GB, 16k cluster */
+    (4194304, 64), /* disks up to 2 GB, 32k cluster */
+    (0xFFFFFFFF, 0) /* any disk greater than 2GB, 0 value for SecPerClusVal trips an error */
+];
+
+const DISK_TABLE_FAT32: [(u32, u32); 6] = [
+    (66600, 0), /* disks up to 32.5 MB, the 0 value for SecPerClusVal trips an error */
+    (532480, 1), /* disks up to 260 MB, .5k cluster */
+    (16777216, 8), /* disks up to 8 GB, 4k cluster */
+    (33554432, 16), /* disks up to 16 GB, 8k cluster */
+    (67108864, 32), /* disks up to 32 GB, 16k cluster */
+    (0xFFFFFFFF, 64)/* disks greater than 32GB, 32k cluster */
+];
+
+#[derive(Clone, Copy)]
+pub struct FormatOptions<'a> {
+    fat_type: Option<FatType>,
+    sectors_in_cluster: Option<u32>,
+    fats_count: u32,
+    reserved_sectors_count: Option<u32>,
+    volume_label: Option<&'a str>,
+    serial: Option<u32>,
+    oem_name: Option<&'a str>,
+    alignment: u32,
+    hidden_sectors: u32,
+}
+
+impl <'a> Default for FormatOptions<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <'a> FormatOptions<'a> {
+    pub fn new() -> Self {
+        Self {
+            fat_type: None,
+            sectors_in_cluster: None,
+            fats_count: FATS_COUNT,
+            reserved_sectors_count: None,
+            volume_label: None,
+            serial: None,
+            oem_name: None,
+            alignment: 1,
+            hidden_sectors: 0,
+        }
+    }
+
+    pub fn fat_type(mut self, fat_type: FatType) -> Self {
+        self.fat_type = Some(fat_type);
+        self
+    }
+
+    pub fn sectors_in_cluster(mut self, sectors_in_cluster: u32) -> Self {
+        self.sectors_in_cluster = Some(sectors_in_cluster);
+        self
+    }
+
+    pub fn fats_count(mut self, fats_count: u32) -> Self {
+        self.fats_count = fats_count;
+        self
+    }
+
+    pub fn reserved_sectors_count(mut self, reserved_sectors_count: u32) -> Self {
+        self.reserved_sectors_count = Some(reserved_sectors_count);
+        self
+    }
+
+    pub fn volume_label(mut self, volume_label: &'a str) -> Self {
+        self.volume_label = Some(volume_label);
+        self
+    }
+
+    pub fn serial(mut self, serial: u32) -> Self {
+        self.serial = Some(serial);
+        self
+    }
+
+    pub fn oem_name(mut self, oem_name: &'a str) -> Self {
+        self.oem_name = Some(oem_name);
+        self
+    }
+
+    // aligns the first data sector to `sectors`, the padding goes to the reserved region
+    pub fn alignment(mut self, sectors: u32) -> Self {
+        self.alignment = sectors;
+        self
+    }
+
+    // sectors preceding the volume on the media, the start of its partition
+    pub fn hidden_sectors(mut self, sectors: u32) -> Self {
+        self.hidden_sectors = sectors;
+        self
+    }
+
+    fn validate(&self, sector_size: u32) -> Result<(), Error> {
+        if let Some(sectors_in_cluster) = self.sectors_in_cluster {
+            if !sectors_in_cluster.is_power_of_two() || sectors_in_cluster > 128 || sectors_in_cluster * sector_size > 32768 {
+                return Err(Error::InvalidClusterSize);
+            }
+        }
+
+        if self.fats_count != 1 && self.fats_count != 2 {
+            return Err(Error::InvalidFatsCount);
+        }
+
+        if let Some(reserved_sectors_count) = self.reserved_sectors_count {
+            if reserved_sectors_count == 0 || reserved_sectors_count > 0xFFFF {
+                return Err(Error::InvalidReservedSectors);
+            }
+        }
+
+        if !self.alignment.is_power_of_two() {
+            return Err(Error::InvalidAlignment);
+        }
+
+        if let Some(label) = self.volume_label {
+            volume_label_bytes(label)?;
+        }
+
+        if let Some(oem_name) = self.oem_name {
+            oem_name_bytes(oem_name)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn volume_label_bytes(label: &str) -> Result<[u8; 11], Error> {
+    let mut buf = [b' '; 11];
+
+    if label.is_empty() || label.len() > buf.len() || label.starts_with(' ') {
+        return Err(Error::InvalidVolumeLabel);
+    }
+
+    for (i, c) in label.bytes().enumerate() {
+        buf[i] = match c {
+            b'a'..=b'z' => c - b'a' + b'A',
+            b'A'..=b'Z' | b'0'..=b'9' | b' ' => c,
+            b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' | b'@' | b'^' | b'_' | b'`' | b'{' | b'}' | b'~' => c,
+            _ => return Err(Error::InvalidVolumeLabel),
+        };
+    }
+
+    Ok(buf)
+}
+
+fn oem_name_bytes(oem_name: &str) -> Result<[u8; 8], Error> {
+    let mut buf = [b' '; 8];
+
+    if oem_name.len() > buf.len() || !oem_name.bytes().all(|c| c.is_ascii_graphic() || c == b' ') {
+        return Err(Error::InvalidOemName);
+    }
+
+    buf[..oem_name.len()].copy_from_slice(oem_name.as_bytes());
+    Ok(buf)
+}
+
+struct Layout {
+    fat_type: FatType,
+    sector_size: u32,
+    sectors_count: u32,
+    sectors_in_cluster: u32,
+    reserved_sectors_count: u32,
+    fats_count: u32,
+    root_entries_count: u32,
+    root_dir_sectors: u32,
+    fat_size_in_sectors: u32,
+    clusters_count: u32,
+}
+
+impl Layout {
+    fn compute(fat_type: FatType, sector_size: u32, sectors_count: u32, sectors_in_cluster: u32, options: &FormatOptions) -> Result<Self, Error> {
+        let (reserved_sectors_count, root_entries_count) = match fat_type {
+            FatType::Fat32 => (options.reserved_sectors_count.unwrap_or(FAT32_RESERVED_SECTORS), 0),
+            FatType::Fat12 | FatType::Fat16 => (options.reserved_sectors_count.unwrap_or(FAT16_RESERVED_SECTORS), FAT16_ROOT_ENTRIES),
+        };
+
+        if fat_type == FatType::Fat32 && reserved_sectors_count <= FAT32_BACKUP_BOOT_SECTOR as u32 + 1 {
+            return Err(Error::InvalidReservedSectors);
+        }
+
+        let root_dir_sectors = (root_entries_count * 32).div_ceil(sector_size);
+        let mut fat_size_in_sectors = 1;
+        let mut padding;
+
+        // grow the FAT until it is able to describe every cluster left after it
+        let clusters_count = loop {
+            let system_sectors = reserved_sectors_count + options.fats_count * fat_size_in_sectors + root_dir_sectors;
+            padding = (options.alignment - system_sectors % options.alignment) % options.alignment;
+            let data_sectors_count = sectors_count.checked_sub(system_sectors + padding).ok_or(Error::VolumeTooSmall)?;
+            let clusters_count = data_sectors_count / sectors_in_cluster;
+            let fat_size = fat_size_in_bytes(fat_type, clusters_count + 2).div_ceil(sector_size);
+
+            if fat_size <= fat_size_in_sectors {
+                break clusters_count;
+            }
+
+            fat_size_in_sectors = fat_size;
+        };
+
+        if clusters_count == 0 {
+            return Err(Error::VolumeTooSmall);
+        }
+
+        if determine_fat_type_by_clusters_count(clusters_count) != fat_type || clusters_count > FAT32_MAX_CLUSTERS {
+            return Err(Error::FatTypeMismatch);
+        }
+
+        if reserved_sectors_count + padding > 0xFFFF {
+            return Err(Error::InvalidAlignment);
+        }
+
+        Ok(Self {
+            fat_type,
+            sector_size,
+            sectors_count,
+            sectors_in_cluster,
+            reserved_sectors_count: reserved_sectors_count + padding,
+            fats_count: options.fats_count,
+            root_entries_count,
+            root_dir_sectors,
+            fat_size_in_sectors,
+            clusters_count,
+        })
+    }
+
+    fn first_fat_table_sector(&self) -> u32 {
+        self.reserved_sectors_count
+    }
+
+    fn first_root_dir_sector(&self) -> u32 {
+        self.reserved_sectors_count + self.fats_count * self.fat_size_in_sectors
+    }
+
+    fn first_data_sector(&self) -> u32 {
+        self.first_root_dir_sector() + self.root_dir_sectors
+    }
+}
+
+fn fat_size_in_bytes(fat_type: FatType, entries: u32) -> u32 {
+    match fat_type {
+        FatType::Fat12 => (entries * 3).div_ceil(2),
+        FatType::Fat16 => entries * 2,
+        FatType::Fat32 => entries * 4,
+    }
+}
+
+fn lookup_sectors_in_cluster(table: &[(u32, u32)], disk_size: u32, sector_size: u32) -> Result<u32, Error> {
+    for &(max_size, sectors_in_cluster) in table {
+        if disk_size <= max_size {
+            if sectors_in_cluster == 0 {
+                return Err(Error::FatTypeMismatch);
+            }
+
+            return Ok(core::cmp::max(1, sectors_in_cluster * 512 / sector_size));
+        }
+    }
+
+    Err(Error::FatTypeMismatch)
+}
+
+fn fat12_layout(sector_size: u32, sectors_count: u32, options: &FormatOptions) -> Result<Layout, Error> {
+    // pick the smallest cluster keeping the clusters count in the FAT12 range
+    let mut sectors_in_cluster = 1;
+
+    loop {
+        match Layout::compute(FatType::Fat12, sector_size, sectors_count, sectors_in_cluster, options) {
+            Ok(layout) => return Ok(layout),
+            Err(e) if sectors_in_cluster >= 128 || sectors_in_cluster * sector_size >= 32768 => return Err(e),
+            Err(_) => sectors_in_cluster *= 2,
+        }
+    }
+}
+
+fn typed_layout(fat_type: FatType, sector_size: u32, sectors_count: u32, options: &FormatOptions) -> Result<Layout, Error> {
+    // the tables are expressed in 512 byte sectors
+    let disk_size = sectors_count.saturating_mul(sector_size / 512);
+
+    let table: &[(u32, u32)] = match fat_type {
+        FatType::Fat12 => return fat12_layout(sector_size, sectors_count, options),
+        FatType::Fat16 => &DISK_TABLE_FAT16,
+        FatType::Fat32 => &DISK_TABLE_FAT32,
+    };
+
+    let sectors_in_cluster = lookup_sectors_in_cluster(table, disk_size, sector_size)?;
+    Layout::compute(fat_type, sector_size, sectors_count, sectors_in_cluster, options)
+}
+
+fn default_layout(sector_size: u32, sectors_count: u32, options: &FormatOptions) -> Result<Layout, Error> {
+    let disk_size = sectors_count.saturating_mul(sector_size / 512);
+
+    if disk_size < FAT12_MAX_SECTORS {
+        fat12_layout(sector_size, sectors_count, options)
+    } else if disk_size <= FAT16_MAX_SECTORS {
+        // big sectors may leave too few clusters for FAT16
+        typed_layout(FatType::Fat16, sector_size, sectors_count, options)
+            .or_else(|_| fat12_layout(sector_size, sectors_count, options))
+    } else {
+        typed_layout(FatType::Fat32, sector_size, sectors_count, options)
+    }
+}
+
+fn choose_layout(sector_size: u32, sectors_count: u32, options: &FormatOptions) -> Result<Layout, Error> {
+    match (options.fat_type, options.sectors_in_cluster) {
+        (Some(fat_type), Some(sectors_in_cluster)) => {
+            Layout::compute(fat_type, sector_size, sectors_count, sectors_in_cluster, options)
+        },
+        (Some(fat_type), None) => typed_layout(fat_type, sector_size, sectors_count, options),
+        (None, Some(sectors_in_cluster)) => {
+            // the clusters count decides which FAT type the volume ends up with
+            let mut result = Err(Error::VolumeTooSmall);
+
+            for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+                result = Layout::compute(fat_type, sector_size, sectors_count, sectors_in_cluster, options);
+
+                if result.is_ok() {
+                    break;
+                }
+            }
+
+            result
+        },
+        (None, None) => default_layout(sector_size, sectors_count, options),
+    }
+}
+
+pub fn format<D: BlockDevice>(dev: &D, options: &FormatOptions) -> Result<(), Error> {
+    format_volume(dev, options).map(|_| ())
+}
+
+pub(crate) fn format_volume<D: BlockDevice>(dev: &D, options: &FormatOptions) -> Result<FatType, Error> {
+    let sector_size = dev.lba_size().or(Err(Error::DeviceIO))?;
+    let sectors_count = dev.count().or(Err(Error::DeviceIO))?;
+
+    if !matches!(sector_size, 512 | 1024 | 2048 | 4096) {
+        return Err(Error::UnsupportedSectorSize);
+    }
+
+    options.validate(sector_size as u32)?;
+    let layout = choose_layout(sector_size as u32, sectors_count, options)?;
+    // there is no entropy source, derive the serial number from the geometry
+    let serial = options.serial.unwrap_or(sectors_count.rotate_left(16) ^ layout.fat_size_in_sectors.wrapping_mul(0x9E37_79B9));
+
+    write_layout(dev, &layout, options, serial)?;
+    dev.flush().or(Err(Error::DeviceIO))?;
+    Ok(layout.fat_type)
+}
+
+fn write_layout<D: BlockDevice>(dev: &D, layout: &Layout, options: &FormatOptions, serial: u32) -> Result<(), Error> {
+    let label = match options.volume_label {
+        Some(label) => Some(volume_label_bytes(label)?),
+        None => None,
+    };
+    let oem_name = match options.oem_name {
+        Some(oem_name) => oem_name_bytes(oem_name)?,
+        None => *OEM_NAME,
+    };
+
+    // wipe everything up to the data region
+    for sector in 0..layout.first_data_sector() {
+        zero_sector(dev, sector, layout.sector_size)?;
+    }
+
+    let boot = boot_sector(layout, &oem_name, label.as_ref().unwrap_or(NO_NAME), serial, options.hidden_sectors);
+    dev.write(0, 0, &boot).or(Err(Error::DeviceIO))?;
+
+    if layout.fat_type == FatType::Fat32 {
+        // root directory occupies the first data cluster
+        for i in 0..layout.sectors_in_cluster {
+            zero_sector(dev, layout.first_data_sector() + i, layout.sector_size)?;
+        }
+
+        let fs_info = fs_info_sector(layout.clusters_count - 1, FAT32_ROOT_CLUSTER + 1);
+        dev.write(FAT32_FS_INFO_SECTOR as u32, 0, &fs_info).or(Err(Error::DeviceIO))?;
+        dev.write(FAT32_BACKUP_BOOT_SECTOR as u32, 0, &boot).or(Err(Error::DeviceIO))?;
+        dev.write(FAT32_BACKUP_BOOT_SECTOR as u32 + 1, 0, &fs_info).or(Err(Error::DeviceIO))?;
+    }
+
+    let mut sector = layout.first_fat_table_sector();
+
+    for _ in 0..layout.fats_count {
+        match layout.fat_type {
+            FatType::Fat12 => {
+                let media = 0xF00 | MEDIA_FIXED_DISK as u32;
+                let entries = media | (0xFFF << 12);
+                dev.write(sector, 0, &entries.to_le_bytes()[..3]).or(Err(Error::DeviceIO))?;
+            },
+            FatType::Fat16 => {
+                let entries = [MEDIA_FIXED_DISK, 0xFF, 0xFF, 0xFF];
+                dev.write(sector, 0, &entries).or(Err(Error::DeviceIO))?;
+            },
+            FatType::Fat32 => {
+                let mut entries = [0u8; 12];
+                entries[0..4].copy_from_slice(&(0x0FFF_FF00 | MEDIA_FIXED_DISK as u32).to_le_bytes());
+                entries[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+                // end of the root directory chain
+                entries[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+                dev.write(sector, 0, &entries).or(Err(Error::DeviceIO))?;
+            },
+        }
+
+        sector += layout.fat_size_in_sectors;
+    }
+
+    if let Some(label) = label {
+        // the root directory starts right after the FATs on every FAT type
+        let mut dir_entry = [0u8; DIR_ENTRY_SIZE];
+        dir_entry[..11].copy_from_slice(&label);
+        dir_entry[11] = ATTR_VOLUME_ID;
+        dev.write(layout.first_root_dir_sector(), 0, &dir_entry).or(Err(Error::DeviceIO))?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn zero_sector<D: BlockDevice>(dev: &D, sector: u32, sector_size: u32) -> Result<(), Error> {
+    let zero_data = [0u8; 512];
+    let mut offset = 0;
+
+    while offset != sector_size as usize {
+        dev.write(sector, offset, &zero_data).or(Err(Error::DeviceIO))?;
+        offset += zero_data.len();
+    }
+
+    Ok(())
+}
+
+fn boot_sector(layout: &Layout, oem_name: &[u8; 8], label: &[u8; 11], serial: u32, hidden_sectors: u32) -> [u8; 512] {
+    let mut boot = [0u8; 512];
+
+    let (sectors_count_16, sectors_count_32) = if layout.sectors_count < 0x10000 && layout.fat_type != FatType::Fat32 {
+        (layout.sectors_count as u16, 0)
+    } else {
+        (0, layout.sectors_count)
+    };
+
+    boot[0..3].copy_from_slice(if layout.fat_type == FatType::Fat32 { &[0xEB, 0x58, 0x90] } else { &[0xEB, 0x3C, 0x90] });
+    boot[3..11].copy_from_slice(oem_name);
+    boot[11..13].copy_from_slice(&(layout.sector_size as u16).to_le_bytes());
+    boot[13] = layout.sectors_in_cluster as u8;
+    boot[14..16].copy_from_slice(&(layout.reserved_sectors_count as u16).to_le_bytes());
+    boot[16] = layout.fats_count as u8;
+    boot[17..19].copy_from_slice(&(layout.root_entries_count as u16).to_le_bytes());
+    boot[19..21].copy_from_slice(&sectors_count_16.to_le_bytes());
+    boot[21] = MEDIA_FIXED_DISK;
+    boot[24..26].copy_from_slice(&63u16.to_le_bytes()); // sectors per track
+    boot[26..28].copy_from_slice(&255u16.to_le_bytes()); // number of heads
+    boot[28..32].copy_from_slice(&hidden_sectors.to_le_bytes());
+    boot[32..36].copy_from_slice(&sectors_count_32.to_le_bytes());
+
+    let ext = if layout.fat_type == FatType::Fat32 {
+        boot[36..40].copy_from_slice(&layout.fat_size_in_sectors.to_le_bytes());
+        boot[44..48].copy_from_slice(&FAT32_ROOT_CLUSTER.to_le_bytes());
+        boot[48..50].copy_from_slice(&FAT32_FS_INFO_SECTOR.to_le_bytes());
+        boot[50..52].copy_from_slice(&FAT32_BACKUP_BOOT_SECTOR.to_le_bytes());
+        64
+    } else {
+        boot[22..24].copy_from_slice(&(layout.fat_size_in_sectors as u16).to_le_bytes());
+        36
+    };
+
+    boot[ext] = DRIVE_NUMBER;
+    boot[ext + 2] = EXT_BOOT_SIGNATURE;
+    boot[ext + 3..ext + 7].copy_from_slice(&serial.to_le_bytes());
+    boot[ext + 7..ext + 18].copy_from_slice(label);
+    boot[ext + 18..ext + 26].copy_from_slice(match layout.fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    boot
+}
+
+fn fs_info_sector(free_count: u32, next_free: u32) -> [u8; 512] {
+    let mut fs_info = [0u8; 512];
+    fs_info[0..4].copy_from_slice(&FS_INFO_LEAD_SIGNATURE.to_le_bytes());
+    fs_info[484..488].copy_from_slice(&FS_INFO_STRUCT_SIGNATURE.to_le_bytes());
+    fs_info[488..492].copy_from_slice(&free_count.to_le_bytes());
+    fs_info[492..496].copy_from_slice(&next_free.to_le_bytes());
+    fs_info[508..512].copy_from_slice(&FS_INFO_TRAIL_SIGNATURE.to_le_bytes());
+    fs_info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clusters_count(fat_type: FatType, sectors_count: u32) -> Result<u32, Error> {
+        Layout::compute(fat_type, 512, sectors_count, 1, &FormatOptions::new()).map(|layout| layout.clusters_count)
+    }
+
+    #[test]
+    fn fat12_fat16_boundary() {
+        assert_eq!(clusters_count(FatType::Fat12, 4143).unwrap(), 4084);
+        assert!(matches!(clusters_count(FatType::Fat12, 4144), Err(Error::FatTypeMismatch)));
+        assert!(matches!(clusters_count(FatType::Fat16, 4151), Err(Error::FatTypeMismatch)));
+        assert_eq!(clusters_count(FatType::Fat16, 4152).unwrap(), 4085);
+    }
+
+    #[test]
+    fn fat16_fat32_boundary() {
+        assert_eq!(clusters_count(FatType::Fat16, 66073).unwrap(), 65524);
+        assert!(matches!(clusters_count(FatType::Fat16, 66074), Err(Error::FatTypeMismatch)));
+        assert!(matches!(clusters_count(FatType::Fat32, 66598), Err(Error::FatTypeMismatch)));
+        assert_eq!(clusters_count(FatType::Fat32, 66599).unwrap(), 65525);
+    }
+
+    #[test]
+    fn fat_fits_clusters() {
+        for (fat_type, sectors_count) in [(FatType::Fat12, 4143), (FatType::Fat16, 66073), (FatType::Fat32, 66599)] {
+            let layout = Layout::compute(fat_type, 512, sectors_count, 1, &FormatOptions::new()).unwrap();
+            assert!(fat_size_in_bytes(fat_type, layout.clusters_count + 2) <= layout.fat_size_in_sectors * 512);
+            assert!(layout.first_data_sector() + layout.clusters_count <= sectors_count);
+        }
+    }
+
+    #[test]
+    fn too_small() {
+        assert!(matches!(clusters_count(FatType::Fat12, 34), Err(Error::VolumeTooSmall)));
+        assert!(matches!(clusters_count(FatType::Fat32, 32), Err(Error::VolumeTooSmall)));
+    }
+}
@@ -1,21 +1,39 @@
+use core::cell::Cell;
 use super::block_device::BlockDevice;
+use super::fat_cache::FatCache;
+use super::code_page::{Cp437, OemCodePage};
+use super::time::{DateTime, NoClock, TimeSource};
 use super::{ClusterValue, FatType, FileSystem, Error};
+use super::format::{self, FormatOptions, FS_INFO_LEAD_SIGNATURE, FS_INFO_STRUCT_SIGNATURE, FS_INFO_TRAIL_SIGNATURE};
 
-pub struct Fs<D: BlockDevice> {
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+struct FsInfo {
+    sector: u32,
+    free_count: Cell<u32>,
+    is_dirty: Cell<bool>,
+}
+
+pub struct Fs<D: BlockDevice, C: OemCodePage = Cp437, T: TimeSource = NoClock> {
     dev: D,
     root_cluster: u32,
     first_data_sector: u32,
-    first_fat_table_sector: u32,
     sectors_in_cluster: u32,
     sector_size: u32,
     clusters_count: u32,
-    fats_count: u32,
-    fat_size_in_sectors: u32,
+    fat: FatCache,
     fat_type: FatType,
     root_dir_sectors: u32,
+    fs_info: Option<FsInfo>,
+    next_free: Cell<u32>,
+    code_page: C,
+    time_source: T,
+    update_access_date: bool,
 }
 
-impl <D: BlockDevice> FileSystem for Fs<D> {
+impl <D: BlockDevice, C: OemCodePage, T: TimeSource> FileSystem for Fs<D, C, T> {
+    type CodePage = C;
+
     fn root_cluster(&self) -> u32 {
         self.root_cluster
     }
@@ -28,14 +46,22 @@ impl <D: BlockDevice> FileSystem for Fs<D> {
         self.clusters_count
     }
 
+    fn root_dir_size(&self) -> usize {
+        (self.root_dir_sectors * self.sector_size) as usize
+    }
+
     fn read(&self, cluster: u32, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
-        let sector = self.cluster_to_sector(cluster)?;
-        self.dev.read(sector, offset % self.sector_size as usize, buf).or(Err(Error::DeviceIO))
+        let sector = self.cluster_to_sector(cluster)? + (offset / self.sector_size as usize) as u32;
+        let offset = offset % self.sector_size as usize;
+        let len = core::cmp::min(buf.len(), self.sector_size as usize - offset);
+        self.dev.read(sector, offset, &mut buf[..len]).or(Err(Error::DeviceIO))
     }
 
     fn write(&self, cluster: u32, offset: usize, buf: &[u8]) -> Result<usize, Error> {
-        let sector = self.cluster_to_sector(cluster)?;
-        self.dev.write(sector, offset % self.sector_size as usize, buf).or(Err(Error::DeviceIO))
+        let sector = self.cluster_to_sector(cluster)? + (offset / self.sector_size as usize) as u32;
+        let offset = offset % self.sector_size as usize;
+        let len = core::cmp::min(buf.len(), self.sector_size as usize - offset);
+        self.dev.write(sector, offset, &buf[..len]).or(Err(Error::DeviceIO))
     }
 
     fn fat_table_get(&self, cluster: u32) -> Result<ClusterValue, Error> {
@@ -54,22 +80,68 @@ impl <D: BlockDevice> FileSystem for Fs<D> {
         }
     }
 
+    fn next_free(&self) -> u32 {
+        self.next_free.get()
+    }
+
+    fn cluster_allocated(&self, cluster: u32) {
+        // next search starts right after the last allocated cluster
+        self.next_free.set(if cluster + 1 < self.clusters_count + 2 { cluster + 1 } else { 2 });
+
+        if let Some(fs_info) = &self.fs_info {
+            if fs_info.free_count.get() != FS_INFO_UNKNOWN {
+                fs_info.free_count.set(fs_info.free_count.get().saturating_sub(1));
+            }
+
+            fs_info.is_dirty.set(true);
+        }
+    }
+
+    fn cluster_freed(&self, _cluster: u32) {
+        if let Some(fs_info) = &self.fs_info {
+            if fs_info.free_count.get() != FS_INFO_UNKNOWN {
+                fs_info.free_count.set(fs_info.free_count.get() + 1);
+                fs_info.is_dirty.set(true);
+            }
+        }
+    }
+
     fn flush(&self) -> Result<(), Error> {
+        self.fat.flush(&self.dev)?;
+
+        if let Some(fs_info) = &self.fs_info {
+            if fs_info.is_dirty.get() {
+                let mut raw = [0u8; 8];
+                raw[..4].copy_from_slice(&fs_info.free_count.get().to_le_bytes());
+                raw[4..].copy_from_slice(&self.next_free.get().to_le_bytes());
+                self.dev.write(fs_info.sector, 488, &raw).or(Err(Error::DeviceIO))?;
+                fs_info.is_dirty.set(false);
+            }
+        }
+
         self.dev.flush().or(Err(Error::DeviceIO))
     }
+
+    fn code_page(&self) -> &C {
+        &self.code_page
+    }
+
+    fn now(&self) -> DateTime {
+        self.time_source.now()
+    }
+
+    fn updates_access_date(&self) -> bool {
+        self.update_access_date
+    }
 }
 
 
-impl <D: BlockDevice> Fs<D> {
+impl <D: BlockDevice, C: OemCodePage, T: TimeSource> Fs<D, C, T> {
     fn fat12_table_get(&self, cluster: u32) -> Result<ClusterValue, Error> {
-        let sector = self.first_fat_table_sector + (cluster + (cluster / 2)) / self.sector_size;
-        let offset = ((cluster + (cluster / 2)) % self.sector_size) as usize;
         let mut raw = [0u8; 2];
-        self.dev.read(sector, offset, &mut raw).or(Err(Error::DeviceIO))?;
+        self.fat.read(&self.dev, cluster + (cluster / 2), &mut raw)?;
         let val = u16::from_le_bytes(raw);
 
-        println!("{:2X} {:2X}", raw[0], raw[1]);
-
         let raw_value = if cluster & 1 == 0 {
             (val & 0x0FFF) as u32
         } else {
@@ -85,10 +157,8 @@ impl <D: BlockDevice> Fs<D> {
     }
 
     fn fat16_table_get(&self, cluster: u32) -> Result<ClusterValue, Error> {
-        let sector = self.first_fat_table_sector + (2 * cluster / self.sector_size);
-        let offset = (2 * cluster % self.sector_size) as usize;
         let mut raw = [0u8; 2];
-        self.dev.read(sector, offset, &mut raw).or(Err(Error::DeviceIO))?;
+        self.fat.read(&self.dev, 2 * cluster, &mut raw)?;
         
         Ok(match u16::from_le_bytes(raw) {
             0 => ClusterValue::Free,
@@ -100,9 +170,7 @@ impl <D: BlockDevice> Fs<D> {
 
     fn fat32_table_get(&self, cluster: u32) -> Result<ClusterValue, Error> {
         let mut raw = [0u8; 4];
-        let sector = self.first_fat_table_sector + (4 * cluster / self.sector_size);
-        let offset = (4 * cluster % self.sector_size) as usize;
-        self.dev.read(sector, offset, &mut raw).or(Err(Error::DeviceIO))?;
+        self.fat.read(&self.dev, 4 * cluster, &mut raw)?;
         
         Ok(match u32::from_le_bytes(raw) & 0x0FFF_FFFF {
             0 => ClusterValue::Free,
@@ -120,15 +188,11 @@ impl <D: BlockDevice> Fs<D> {
             ClusterValue::Next(x) => x,
         };
 
-        let mut sector = self.first_fat_table_sector + (4 * cluster / self.sector_size);
-        let offset = (4 * cluster % self.sector_size) as usize;
-
-        for _ in 0..self.fats_count {
-            self.dev.write(sector, offset, &n.to_le_bytes()).or(Err(Error::DeviceIO))?;
-            sector += self.fat_size_in_sectors;
-        }
-        
-        Ok(())
+        // the upper 4 bits are reserved and have to be preserved
+        let mut raw = [0u8; 4];
+        self.fat.read(&self.dev, 4 * cluster, &mut raw)?;
+        let n = (u32::from_le_bytes(raw) & 0xF000_0000) | (n & 0x0FFF_FFFF);
+        self.fat.write(&self.dev, 4 * cluster, &n.to_le_bytes())
     }
 
     fn fat16_table_set(&self, cluster: u32, value: ClusterValue) -> Result<(), Error> {
@@ -139,15 +203,7 @@ impl <D: BlockDevice> Fs<D> {
             ClusterValue::Next(x) => x,
         } as u16;
 
-        let mut sector = self.first_fat_table_sector + (2 * cluster / self.sector_size);
-        let offset = (2 * cluster % self.sector_size) as usize;
-
-        for _ in 0..self.fats_count {
-            self.dev.write(sector, offset, &n.to_le_bytes()).or(Err(Error::DeviceIO))?;
-            sector += self.fat_size_in_sectors;
-        }
-        
-        Ok(())
+        self.fat.write(&self.dev, 2 * cluster, &n.to_le_bytes())
     }
 
     fn fat12_table_set(&self, cluster: u32, value: ClusterValue) -> Result<(), Error> {
@@ -158,60 +214,46 @@ impl <D: BlockDevice> Fs<D> {
             ClusterValue::Bad => 0xFF7,
         };
 
-        let mut sector = self.first_fat_table_sector + (cluster + (cluster / 2)) / self.sector_size;
-        let offset = ((cluster + (cluster / 2)) % self.sector_size) as usize;
+        let offset = cluster + (cluster / 2);
         let mut raw = [0u8; 2];
-        self.dev.read(sector, offset, &mut raw).or(Err(Error::DeviceIO))?;
+        self.fat.read(&self.dev, offset, &mut raw)?;
 
         if cluster & 1 == 0 {
             raw[0] = raw_value as u8;
-            raw[1] = (raw[1] & 0x0f) | (((raw_value >> 8) & 0x0f) as u8);
+            raw[1] = (raw[1] & 0xf0) | (((raw_value >> 8) & 0x0f) as u8);
         } else {
-            raw[0] = (raw[0] & 0xf0) | (((raw_value & 0x0f) << 4) as u8);
-            raw[1] = (raw_value >> 8) as u8;
-        }
-        
-        for _ in 0..self.fats_count {
-            self.dev.write(sector, offset, &raw).or(Err(Error::DeviceIO))?;
-            sector += self.fat_size_in_sectors;
+            raw[0] = (raw[0] & 0x0f) | (((raw_value & 0x0f) << 4) as u8);
+            raw[1] = (raw_value >> 4) as u8;
         }
         
-        Ok(())
+        self.fat.write(&self.dev, offset, &raw)
     }
 
-    pub fn mount(dev: D) -> Result<Self, Error> {
+    // short names are read and written in `code_page`, new and changed entries
+    // are stamped with the time from `time_source`
+    pub fn mount_with(dev: D, code_page: C, time_source: T) -> Result<Self, Error> {
         let mut boot = [0u8; 512];
-        dev.read(0, 0, &mut boot).or(Err(Error::DeviceIO)).or(Err(Error::DeviceIO))?;
+        dev.read(0, 0, &mut boot).or(Err(Error::DeviceIO))?;
 
         let sector_size = u16::from_le_bytes([boot[11], boot[12]]) as u32;
-        println!("sector size: {}", sector_size);
 
         let sectors_in_cluster = boot[13] as u32;
-        println!("sectors in cluster: {}", sectors_in_cluster);
 
         let reserved_sectors_count = u16::from_le_bytes([boot[14], boot[15]]) as u32;
-        println!("reserved sectors count: {}", reserved_sectors_count);
 
         let fats_count = boot[16] as u32;
-        println!("fats count: {}", fats_count);
 
         let root_entries_count = u16::from_le_bytes([boot[17], boot[18]]) as u32;
-        println!("root entries count: {}", root_entries_count);
 
         let sectors_count_16 = u16::from_le_bytes([boot[19], boot[20]]) as u32;
-        println!("sectors count (FAT12 and FAT16): {}", sectors_count_16);
 
         let fat_size_in_sectors_16 = u16::from_le_bytes([boot[22], boot[23]]) as u32;
-        println!("fat_size_in_sectors (FAT12 and FAT16): {}", fat_size_in_sectors_16);
 
         let sectors_count_32 = u32::from_le_bytes([boot[32], boot[33], boot[34], boot[35]]);
-        println!("sectors count (FAT32): {}", sectors_count_32);
 
         let fat_size_in_sectors_32 = u32::from_le_bytes([boot[36], boot[37], boot[38], boot[39]]);
-        println!("fat_size_in_sectors (FAT32): {}", fat_size_in_sectors_32);
 
-        let root_dir_sectors = ((root_entries_count * 32) + (sector_size - 1)) / sector_size;
-        println!("root_dir_sectors: {}", root_dir_sectors);
+        let root_dir_sectors = (root_entries_count * 32).div_ceil(sector_size);
 
         let fat_size_in_sectors =  if fat_size_in_sectors_16 != 0{
             fat_size_in_sectors_16
@@ -230,7 +272,6 @@ impl <D: BlockDevice> Fs<D> {
         let first_data_sector = reserved_sectors_count + (fats_count * fat_size_in_sectors) + root_dir_sectors;
         let first_fat_table_sector = reserved_sectors_count;
         let fat_type = determine_fat_type_by_clusters_count(clusters_count);
-        println!("{:?}", fat_type);
 
         let root_cluster = if fat_type == FatType::Fat32 {
             u32::from_le_bytes([boot[44], boot[45], boot[46], boot[47]])
@@ -238,23 +279,75 @@ impl <D: BlockDevice> Fs<D> {
             0
         };
 
+        let (fs_info, next_free) = if fat_type == FatType::Fat32 {
+            let sector = u16::from_le_bytes([boot[48], boot[49]]) as u32;
+            read_fs_info(&dev, sector, clusters_count)?
+        } else {
+            (None, 2)
+        };
+
         Ok(Self {
-            dev: dev,
+            dev,
             root_cluster,
             first_data_sector,
-            first_fat_table_sector,
             sectors_in_cluster,
             sector_size,
             clusters_count,
-            fats_count,
-            fat_size_in_sectors,
+            fat: FatCache::new(first_fat_table_sector, sector_size, fat_size_in_sectors, fats_count),
             fat_type,
             root_dir_sectors,
+            fs_info,
+            next_free: Cell::new(next_free),
+            code_page,
+            time_source,
+            update_access_date: false,
         })
     }
 
+    // reads set the access date of files, which costs a write on flush once a day
+    pub fn update_access_date(mut self, update: bool) -> Self {
+        self.update_access_date = update;
+        self
+    }
+
+    pub fn unmount(self) -> Result<D, Error> {
+        self.flush()?;
+        Ok(self.dev)
+    }
+
+    pub fn free_clusters(&self) -> Result<u32, Error> {
+        if let Some(fs_info) = &self.fs_info {
+            if fs_info.free_count.get() != FS_INFO_UNKNOWN {
+                return Ok(fs_info.free_count.get());
+            }
+        }
+
+        let mut free_count = 0;
+
+        for cluster in 2..self.clusters_count + 2 {
+            if let ClusterValue::Free = self.fat_table_get(cluster)? {
+                free_count += 1;
+            }
+        }
+
+        if let Some(fs_info) = &self.fs_info {
+            fs_info.free_count.set(free_count);
+            fs_info.is_dirty.set(true);
+        }
+
+        Ok(free_count)
+    }
+
+    pub(crate) fn fats_count(&self) -> u32 {
+        self.fat.fats_count()
+    }
+
+    pub(crate) fn fat_copy_mismatch(&self, copy: u32) -> Result<Option<u32>, Error> {
+        self.fat.copy_mismatch(&self.dev, copy)
+    }
+
     pub fn cluster_to_sector(&self, cluster: u32) -> Result<u32, Error> {
-        if cluster >= self.cluster_count() {
+        if cluster >= self.clusters_count + 2 {
             return Err(Error::InvalidClusterNumber);
         }
 
@@ -280,15 +373,57 @@ impl <D: BlockDevice> Fs<D> {
 impl <D: BlockDevice> Fs<D> 
 //    where Error: From<<D as BlockDevice>::Error>
 {
-    pub fn format(dev: &D) -> Result<(), D::Error> {
-        println!("Formating...");
-        println!("Sectors count: {}", dev.count()?);
-        println!("Sectors size: {}", dev.lba_size()?);
-        Ok(())
+    pub fn mount(dev: D) -> Result<Self, Error> {
+        Self::mount_with(dev, Cp437, NoClock)
     }
+
+    pub fn format(dev: &D) -> Result<(), Error> {
+        format::format(dev, &FormatOptions::new())
+    }
+
+    pub fn format_with(dev: &D, options: &FormatOptions) -> Result<(), Error> {
+        format::format(dev, options)
+    }
+}
+
+fn read_fs_info<D: BlockDevice>(dev: &D, sector: u32, clusters_count: u32) -> Result<(Option<FsInfo>, u32), Error> {
+    if sector == 0 || sector == 0xFFFF {
+        return Ok((None, 2));
+    }
+
+    let mut raw = [0u8; 512];
+    dev.read(sector, 0, &mut raw).or(Err(Error::DeviceIO))?;
+
+    let lead_signature = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+    let struct_signature = u32::from_le_bytes([raw[484], raw[485], raw[486], raw[487]]);
+    let trail_signature = u32::from_le_bytes([raw[508], raw[509], raw[510], raw[511]]);
+
+    if lead_signature != FS_INFO_LEAD_SIGNATURE || struct_signature != FS_INFO_STRUCT_SIGNATURE || trail_signature != FS_INFO_TRAIL_SIGNATURE {
+        return Ok((None, 2));
+    }
+
+    let mut free_count = u32::from_le_bytes([raw[488], raw[489], raw[490], raw[491]]);
+    let mut next_free = u32::from_le_bytes([raw[492], raw[493], raw[494], raw[495]]);
+
+    // both fields are only hints, drop values that can't be right
+    if free_count > clusters_count {
+        free_count = FS_INFO_UNKNOWN;
+    }
+
+    if next_free < 2 || next_free >= clusters_count + 2 {
+        next_free = 2;
+    }
+
+    let fs_info = FsInfo {
+        sector,
+        free_count: Cell::new(free_count),
+        is_dirty: Cell::new(false),
+    };
+
+    Ok((Some(fs_info), next_free))
 }
 
-fn determine_fat_type_by_clusters_count(count: u32) -> FatType {
+pub(crate) fn determine_fat_type_by_clusters_count(count: u32) -> FatType {
     if count < 4085 {
         FatType::Fat12
     } else if count < 65525 {
@@ -297,24 +432,3 @@ fn determine_fat_type_by_clusters_count(count: u32) -> FatType {
         FatType::Fat32
     }
 }
-
-//const DISK_TABLE_FAT16: [(usize, usize); 8] = [
-//    (8400, 0), /* disks up to 4.1 MB, the 0 value for SecPerClusVal trips an error */
-//    (32680, 2), /* disks up to 16 MB, 1k cluster */
-//    (262144, 4), /* disks up to 128 MB, 2k cluster */
-//    (524288, 8), /* disks up to 256 MB, 4k cluster */
-//    (1048576, 16), /* disks up to 512 MB, 8k cluster */
-//    /* The entries after this point are not used unless FAT16 is forced */
-//    (2097152, 32), /* disks up to 1 GB, 16k cluster */
-//    (4194304, 64), /* disks up to 2 GB, 32k cluster */
-//    (0xFFFFFFFF, 0) /* any disk greater than 2GB, 0 value for SecPerClusVal trips an error */
-//];
-//
-//const DISK_TABLE_FAT32: [(usize, usize); 6] = [
-//    (66600, 0), /* disks up to 32.5 MB, the 0 value for SecPerClusVal trips an error */
-//    (532480, 1), /* disks up to 260 MB, .5k cluster */
-//    (16777216, 8), /* disks up to 8 GB, 4k cluster */
-//    (33554432, 16), /* disks up to 16 GB, 8k cluster */
-//    (67108864, 32), /* disks up to 32 GB, 16k cluster */
-//    (0xFFFFFFFF, 64)/* disks greater than 32GB, 32k cluster */
-//];
\ No newline at end of file
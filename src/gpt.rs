@@ -0,0 +1,468 @@
+use super::block_device::BlockDevice;
+use super::format;
+use super::partition::{PartitionOptions, Volume};
+use super::Error;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_LBA: u32 = 1;
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_HEADER_MAX_SIZE: usize = 512;
+const GPT_ENTRY_MIN_SIZE: u32 = 128;
+const GPT_NAME_LEN: usize = 36;
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_ENTRIES_COUNT: u32 = 128;
+const PARTITION_PROTECTIVE: u8 = 0xEE;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const ZERO: Guid = Guid([0u8; 16]);
+    pub const MICROSOFT_BASIC_DATA: Guid = Guid::new(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+
+    // fields as written in the text form, the first three are stored little endian
+    pub const fn new(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> Self {
+        let a = d1.to_le_bytes();
+        let b = d2.to_le_bytes();
+        let c = d3.to_le_bytes();
+
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1],
+            d4[0], d4[1], d4[2], d4[3], d4[4], d4[5], d4[6], d4[7],
+        ])
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Guid(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct GptEntry {
+    type_guid: Guid,
+    unique_guid: Guid,
+    first_lba: u64,
+    last_lba: u64,
+    attributes: u64,
+    name: [u16; GPT_NAME_LEN],
+}
+
+impl GptEntry {
+    fn from_raw(raw: &[u8; GPT_ENTRY_MIN_SIZE as usize]) -> Self {
+        let mut name = [0u16; GPT_NAME_LEN];
+
+        for (i, c) in name.iter_mut().enumerate() {
+            *c = u16::from_le_bytes([raw[56 + 2 * i], raw[57 + 2 * i]]);
+        }
+
+        Self {
+            type_guid: Guid(raw[0..16].try_into().unwrap()),
+            unique_guid: Guid(raw[16..32].try_into().unwrap()),
+            first_lba: u64::from_le_bytes(raw[32..40].try_into().unwrap()),
+            last_lba: u64::from_le_bytes(raw[40..48].try_into().unwrap()),
+            attributes: u64::from_le_bytes(raw[48..56].try_into().unwrap()),
+            name,
+        }
+    }
+
+    pub fn type_guid(&self) -> Guid {
+        self.type_guid
+    }
+
+    pub fn unique_guid(&self) -> Guid {
+        self.unique_guid
+    }
+
+    pub fn first_lba(&self) -> u64 {
+        self.first_lba
+    }
+
+    pub fn last_lba(&self) -> u64 {
+        self.last_lba
+    }
+
+    pub fn attributes(&self) -> u64 {
+        self.attributes
+    }
+
+    pub fn name(&self) -> &[u16] {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(GPT_NAME_LEN);
+        &self.name[..len]
+    }
+
+    pub fn compare_name(&self, name: &str) -> bool {
+        self.name().iter().copied().eq(name.encode_utf16())
+    }
+}
+
+struct GptHeader {
+    entries_lba: u64,
+    entries_count: u32,
+    entry_size: u32,
+}
+
+pub struct Gpt<D> {
+    drive: D,
+    header: GptHeader,
+    is_backup: bool,
+}
+
+impl <D: BlockDevice> Gpt<D> {
+    pub fn new(drive: D) -> Result<Self, Error> {
+        check_protective_mbr(&drive)?;
+
+        if let Ok(header) = read_header(&drive, GPT_HEADER_LBA) {
+            return Ok(Self {
+                drive,
+                header,
+                is_backup: false,
+            });
+        }
+
+        // the primary header is damaged, the backup lives in the last sector
+        let last_lba = drive.count().or(Err(Error::DeviceIO))?.checked_sub(1).ok_or(Error::InvalidPartitionTable)?;
+        let header = read_header(&drive, last_lba)?;
+
+        Ok(Self {
+            drive,
+            header,
+            is_backup: true,
+        })
+    }
+
+    pub fn is_backup(&self) -> bool {
+        self.is_backup
+    }
+
+    pub fn entries_count(&self) -> u32 {
+        self.header.entries_count
+    }
+
+    // unused slots are returned as `None`
+    pub fn entry(&self, n: u32) -> Result<Option<GptEntry>, Error> {
+        if n >= self.header.entries_count {
+            return Err(Error::NotFound);
+        }
+
+        let lba_size = self.drive.lba_size().or(Err(Error::DeviceIO))? as u64;
+        let pos = n as u64 * self.header.entry_size as u64;
+        let lba = self.header.entries_lba + pos / lba_size;
+        let mut raw = [0u8; GPT_ENTRY_MIN_SIZE as usize];
+        self.drive.read(to_lba(lba)?, (pos % lba_size) as usize, &mut raw).or(Err(Error::DeviceIO))?;
+
+        let entry = GptEntry::from_raw(&raw);
+
+        if entry.type_guid == Guid::ZERO {
+            return Ok(None);
+        }
+
+        Ok(Some(entry))
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = Result<(u32, GptEntry), Error>> + '_ {
+        (0..self.header.entries_count).filter_map(|n| match self.entry(n) {
+            Ok(Some(entry)) => Some(Ok((n, entry))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    pub fn volume(&self, n: u32) -> Result<Volume<'_, D>, Error> {
+        let entry = self.entry(n)?.ok_or(Error::NotFound)?;
+        self.entry_volume(&entry)
+    }
+
+    pub fn volume_by_type(&self, type_guid: &Guid) -> Result<Volume<'_, D>, Error> {
+        for entry in self.entries() {
+            let (_, entry) = entry?;
+
+            if entry.type_guid == *type_guid {
+                return self.entry_volume(&entry);
+            }
+        }
+
+        Err(Error::NotFound)
+    }
+
+    pub fn volume_by_name(&self, name: &str) -> Result<Volume<'_, D>, Error> {
+        for entry in self.entries() {
+            let (_, entry) = entry?;
+
+            if entry.compare_name(name) {
+                return self.entry_volume(&entry);
+            }
+        }
+
+        Err(Error::NotFound)
+    }
+
+    pub fn into_inner(self) -> D {
+        self.drive
+    }
+
+    fn entry_volume(&self, entry: &GptEntry) -> Result<Volume<'_, D>, Error> {
+        if entry.last_lba < entry.first_lba {
+            return Err(Error::InvalidPartitionTable);
+        }
+
+        let start = to_lba(entry.first_lba)?;
+        let count = to_lba(entry.last_lba - entry.first_lba + 1)?;
+        Volume::new(&self.drive, start, count)
+    }
+}
+
+// formats every partition and writes a protective MBR, a GPT and its backup,
+// the partitions get the unique GUID of the disk with the index added to it
+pub fn create_gpt<D: BlockDevice>(dev: &D, disk_guid: Guid, partitions: &[PartitionOptions]) -> Result<(), Error> {
+    if partitions.len() > GPT_ENTRIES_COUNT as usize {
+        return Err(Error::TooManyPartitions);
+    }
+
+    if partitions.iter().any(|p| p.partition_name().encode_utf16().count() > GPT_NAME_LEN) {
+        return Err(Error::InvalidPartitionName);
+    }
+
+    let lba_size = dev.lba_size().or(Err(Error::DeviceIO))?;
+    let count = dev.count().or(Err(Error::DeviceIO))?;
+    let entries_sectors = (GPT_ENTRIES_COUNT * GPT_ENTRY_MIN_SIZE).div_ceil(lba_size as u32);
+    let entries_lba = GPT_HEADER_LBA + 1;
+    let first_usable = entries_lba + entries_sectors;
+    let backup_lba = count.checked_sub(1).ok_or(Error::VolumeTooSmall)?;
+    let backup_entries_lba = backup_lba.checked_sub(entries_sectors).ok_or(Error::VolumeTooSmall)?;
+
+    if first_usable >= backup_entries_lba {
+        return Err(Error::VolumeTooSmall);
+    }
+
+    let mut ranges = [(0u32, 0u32); GPT_ENTRIES_COUNT as usize];
+    let mut next = first_usable;
+
+    for (range, partition) in ranges.iter_mut().zip(partitions) {
+        let (start, count) = partition.place(lba_size, next, backup_entries_lba)?;
+        partition.format(dev, start, count)?;
+        *range = (start, count);
+        next = start + count;
+    }
+
+    let mut entries_crc = !0;
+
+    for n in 0..GPT_ENTRIES_COUNT {
+        let mut raw = [0u8; GPT_ENTRY_MIN_SIZE as usize];
+
+        if let Some(partition) = partitions.get(n as usize) {
+            let (start, count) = ranges[n as usize];
+            let mut unique_guid = disk_guid;
+            let d1 = u32::from_le_bytes(disk_guid.0[0..4].try_into().unwrap()).wrapping_add(n + 1);
+            unique_guid.0[0..4].copy_from_slice(&d1.to_le_bytes());
+
+            raw[0..16].copy_from_slice(Guid::MICROSOFT_BASIC_DATA.as_bytes());
+            raw[16..32].copy_from_slice(unique_guid.as_bytes());
+            raw[32..40].copy_from_slice(&(start as u64).to_le_bytes());
+            raw[40..48].copy_from_slice(&((start + count - 1) as u64).to_le_bytes());
+
+            for (i, c) in partition.partition_name().encode_utf16().enumerate() {
+                raw[56 + 2 * i..58 + 2 * i].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        entries_crc = crc32(entries_crc, &raw);
+
+        let pos = n * GPT_ENTRY_MIN_SIZE;
+        let offset = (pos % lba_size as u32) as usize;
+        dev.write(entries_lba + pos / lba_size as u32, offset, &raw).or(Err(Error::DeviceIO))?;
+        dev.write(backup_entries_lba + pos / lba_size as u32, offset, &raw).or(Err(Error::DeviceIO))?;
+    }
+
+    let last_usable = backup_entries_lba - 1;
+    let header = |my_lba: u32, alternate_lba: u32, entries_lba: u32| {
+        let mut raw = [0u8; GPT_HEADER_MAX_SIZE];
+        raw[0..8].copy_from_slice(GPT_SIGNATURE);
+        raw[8..12].copy_from_slice(&GPT_REVISION.to_le_bytes());
+        raw[12..16].copy_from_slice(&(GPT_HEADER_MIN_SIZE as u32).to_le_bytes());
+        raw[24..32].copy_from_slice(&(my_lba as u64).to_le_bytes());
+        raw[32..40].copy_from_slice(&(alternate_lba as u64).to_le_bytes());
+        raw[40..48].copy_from_slice(&(first_usable as u64).to_le_bytes());
+        raw[48..56].copy_from_slice(&(last_usable as u64).to_le_bytes());
+        raw[56..72].copy_from_slice(disk_guid.as_bytes());
+        raw[72..80].copy_from_slice(&(entries_lba as u64).to_le_bytes());
+        raw[80..84].copy_from_slice(&GPT_ENTRIES_COUNT.to_le_bytes());
+        raw[84..88].copy_from_slice(&GPT_ENTRY_MIN_SIZE.to_le_bytes());
+        raw[88..92].copy_from_slice(&(!entries_crc).to_le_bytes());
+        let crc = !crc32(!0, &raw[..GPT_HEADER_MIN_SIZE]);
+        raw[16..20].copy_from_slice(&crc.to_le_bytes());
+        raw
+    };
+
+    format::zero_sector(dev, backup_lba, lba_size as u32)?;
+    dev.write(backup_lba, 0, &header(backup_lba, GPT_HEADER_LBA, backup_entries_lba)).or(Err(Error::DeviceIO))?;
+    format::zero_sector(dev, GPT_HEADER_LBA, lba_size as u32)?;
+    dev.write(GPT_HEADER_LBA, 0, &header(GPT_HEADER_LBA, backup_lba, entries_lba)).or(Err(Error::DeviceIO))?;
+
+    // one partition covering the whole media keeps MBR only tools off it
+    let mut mbr = [0u8; 512];
+    let raw = &mut mbr[446..462];
+    raw[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    raw[4] = PARTITION_PROTECTIVE;
+    raw[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    raw[8..12].copy_from_slice(&GPT_HEADER_LBA.to_le_bytes());
+    raw[12..16].copy_from_slice(&backup_lba.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    format::zero_sector(dev, 0, lba_size as u32)?;
+    dev.write(0, 0, &mbr).or(Err(Error::DeviceIO))?;
+    dev.flush().or(Err(Error::DeviceIO))
+}
+
+fn to_lba(lba: u64) -> Result<u32, Error> {
+    u32::try_from(lba).or(Err(Error::OutOfBounds))
+}
+
+fn check_protective_mbr<D: BlockDevice>(drive: &D) -> Result<(), Error> {
+    let mut mbr = [0u8; 512];
+    drive.read(0, 0, &mut mbr).or(Err(Error::DeviceIO))?;
+
+    if mbr[510] != 0x55 || mbr[511] != 0xAA {
+        return Err(Error::InvalidPartitionTable);
+    }
+
+    let is_protective = (0..4).any(|i| {
+        let raw = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        raw[4] == PARTITION_PROTECTIVE && u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]) == GPT_HEADER_LBA
+    });
+
+    if !is_protective {
+        return Err(Error::InvalidPartitionTable);
+    }
+
+    Ok(())
+}
+
+fn read_header<D: BlockDevice>(drive: &D, lba: u32) -> Result<GptHeader, Error> {
+    let lba_size = drive.lba_size().or(Err(Error::DeviceIO))?;
+    let mut raw = [0u8; GPT_HEADER_MAX_SIZE];
+    drive.read(lba, 0, &mut raw).or(Err(Error::DeviceIO))?;
+
+    let header_size = u32::from_le_bytes(raw[12..16].try_into().unwrap()) as usize;
+
+    if &raw[0..8] != GPT_SIGNATURE || !(GPT_HEADER_MIN_SIZE..=GPT_HEADER_MAX_SIZE).contains(&header_size) {
+        return Err(Error::InvalidPartitionTable);
+    }
+
+    let header_crc = u32::from_le_bytes(raw[16..20].try_into().unwrap());
+    raw[16..20].copy_from_slice(&[0u8; 4]);
+
+    if !crc32(!0, &raw[..header_size]) != header_crc {
+        return Err(Error::InvalidPartitionTable);
+    }
+
+    let my_lba = u64::from_le_bytes(raw[24..32].try_into().unwrap());
+    let entries_lba = u64::from_le_bytes(raw[72..80].try_into().unwrap());
+    let entries_count = u32::from_le_bytes(raw[80..84].try_into().unwrap());
+    let entry_size = u32::from_le_bytes(raw[84..88].try_into().unwrap());
+    let entries_crc = u32::from_le_bytes(raw[88..92].try_into().unwrap());
+
+    if my_lba != lba as u64 || !entry_size.is_power_of_two() || entry_size < GPT_ENTRY_MIN_SIZE || entry_size as usize > lba_size {
+        return Err(Error::InvalidPartitionTable);
+    }
+
+    // the entries array is checked in 512 byte chunks, it's too big for the stack
+    let len = entries_count as u64 * entry_size as u64;
+    let mut crc = !0;
+    let mut pos = 0;
+
+    while pos < len {
+        let mut buf = [0u8; 512];
+        let chunk = core::cmp::min(len - pos, buf.len() as u64) as usize;
+        let lba = to_lba(entries_lba + pos / lba_size as u64)?;
+        drive.read(lba, (pos % lba_size as u64) as usize, &mut buf[..chunk]).or(Err(Error::DeviceIO))?;
+        crc = crc32(crc, &buf[..chunk]);
+        pos += chunk as u64;
+    }
+
+    if !crc != entries_crc {
+        return Err(Error::InvalidPartitionTable);
+    }
+
+    Ok(GptHeader {
+        entries_lba,
+        entries_count,
+        entry_size,
+    })
+}
+
+// CRC-32 as used by GPT, start with !0 and invert the result
+pub(crate) fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_device::RamDevice;
+
+    const DISK_GUID: Guid = Guid::new(0x12345678, 0x9ABC, 0xDEF0, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+    fn disk() -> RamDevice {
+        let dev = RamDevice::new(4096, 512);
+        create_gpt(&dev, DISK_GUID, &[PartitionOptions::new().name("data")]).unwrap();
+        dev
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(!crc32(!0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(!crc32(!0, b""), 0);
+        // the state carries over between chunks
+        assert_eq!(crc32(crc32(!0, b"1234"), b"56789"), crc32(!0, b"123456789"));
+    }
+
+    #[test]
+    fn read_primary_header() {
+        let gpt = Gpt::new(disk()).unwrap();
+        assert!(!gpt.is_backup());
+        assert_eq!(gpt.entries_count(), GPT_ENTRIES_COUNT);
+        assert!(gpt.volume_by_name("data").is_ok());
+    }
+
+    #[test]
+    fn fall_back_to_backup_header() {
+        let dev = disk();
+        dev.write(GPT_HEADER_LBA, 16, &[0u8; 4]).unwrap();
+        assert!(read_header(&dev, GPT_HEADER_LBA).is_err());
+
+        let gpt = Gpt::new(dev).unwrap();
+        assert!(gpt.is_backup());
+        assert_eq!(gpt.entries_count(), GPT_ENTRIES_COUNT);
+        assert!(gpt.volume_by_name("data").is_ok());
+    }
+
+    #[test]
+    fn both_headers_damaged() {
+        let dev = disk();
+        dev.write(GPT_HEADER_LBA, 0, b"NOT PART").unwrap();
+        dev.write(dev.count().unwrap() - 1, 0, b"NOT PART").unwrap();
+        assert!(matches!(Gpt::new(dev), Err(Error::InvalidPartitionTable)));
+    }
+
+    #[test]
+    fn damaged_entries() {
+        let dev = disk();
+        // the entries of the primary table follow its header
+        dev.write(GPT_HEADER_LBA + 1, 56, &[0u8; 2]).unwrap();
+        assert!(read_header(&dev, GPT_HEADER_LBA).is_err());
+        assert!(Gpt::new(dev).unwrap().is_backup());
+    }
+}
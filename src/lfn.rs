@@ -6,57 +6,75 @@ use super::{FileSystem, Error};
 const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
 const ATTR_LONG_NAME_MASK: u8 = ATTR_LONG_NAME | ATTR_DIRECTORY | ATTR_ARCHIVE;
 
-pub const LFN_MAX_LEN: usize = 256;
+// a name is at most 255 UTF-16 units, each one takes up to 3 bytes in UTF-8
+pub const LFN_MAX_LEN: usize = 255;
+const LFN_BUF_SIZE: usize = LFN_MAX_LEN * 3;
+const LFN_MAX_ENTRIES: usize = LFN_MAX_LEN.div_ceil(CHAR_ORDER.len());
 const LAST_LONG_ENTRY: u8 = 0x40;
-const LAST_LONG_ENTRY_MASK: u8 = 0xf0;
+const LFN_PADDING: u16 = 0xFFFF;
 
 const CHAR_ORDER: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
 
 pub struct Lfn {
-    buf: [u8; LFN_MAX_LEN],
+    buf: [u8; LFN_BUF_SIZE],
     len: usize,
 }
 
 impl Lfn {
     pub fn new() -> Self {
         Self {
-            buf: [0u8; LFN_MAX_LEN],
+            buf: [0u8; LFN_BUF_SIZE],
             len: 0,
         }
     }
 
-    pub fn compare(&self, name: &str) -> bool {
-        println!("lfn name len: {}, compared name len: {}", self.len, name.len());
+    fn decode(&mut self, units: &[u16]) {
+        self.len = 0;
 
-        if name.len() != self.len {
-            return false;
-        }
-
-        for (i, &c) in name.as_bytes().iter().enumerate() {
-            if self.buf[i] != c {
-                return false;
-            }
+        // unpaired surrogates can't be represented in UTF-8
+        for c in char::decode_utf16(units.iter().copied()) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            self.len += c.encode_utf8(&mut self.buf[self.len..]).len();
         }
+    }
 
-        true
+    // long names keep their case but are matched ignoring it
+    pub fn compare(&self, name: &str) -> bool {
+        eq_ignore_case(self.name(), name)
     }
 
-    pub fn name(&self) -> &[u8] {
-        &self.buf[..self.len]
+    pub fn name(&self) -> &str {
+        // only ever filled from whole chars
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
     }
+}
 
-    pub fn print_name(&self) {
-        for &c in &self.buf[..self.len] {
-            print!("{}", c as char);
-        }
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.chars().map(fold_case).eq(b.chars().map(fold_case))
+}
+
+// simple case folding, chars without a single char mapping stay as they are
+fn fold_case(c: char) -> char {
+    let mut upper = c.to_uppercase();
 
-        println!();
+    let c = match (upper.next(), upper.next()) {
+        (Some(upper), None) => upper,
+        _ => c,
+    };
+
+    let mut lower = c.to_lowercase();
+
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => lower,
+        _ => c,
     }
 }
 
 pub struct LfnBuilder<'a> {
     lfn: &'a mut Lfn,
     lfn_pos: Stream,
+    units: [u16; LFN_MAX_ENTRIES * CHAR_ORDER.len()],
+    len: usize,
     checksum: u8,
     number: u8,
 }
@@ -66,6 +84,8 @@ impl <'a> LfnBuilder<'a> {
         Self { 
             lfn,
             lfn_pos: Stream::open(0),
+            units: [0u16; LFN_MAX_ENTRIES * CHAR_ORDER.len()],
+            len: 0,
             checksum: 0,
             number: 0,
         }
@@ -76,24 +96,30 @@ impl <'a> LfnBuilder<'a> {
     }
 
     pub fn process(&mut self, pos: Stream, buf: &[u8; DIR_ENTRY_SIZE]) -> bool {
-        if buf[11] & ATTR_LONG_NAME_MASK != ATTR_LONG_NAME {
+        if !is_lfn_entry(buf) {
             // not LFN
             return false;
         }
 
-        if buf[0] & LAST_LONG_ENTRY_MASK == LAST_LONG_ENTRY {
+        if is_last_lfn_entry(buf) {
             // begining of LFN
             self.checksum = buf[13];
-            self.number = buf[0] & 0x0f;
+            self.number = buf[0] & 0x1f;
             self.lfn_pos = pos;
-            self.lfn.len = self.number as usize * CHAR_ORDER.len();
+            self.len = self.number as usize * CHAR_ORDER.len();
+
+            if self.number as usize > LFN_MAX_ENTRIES {
+                // longer than any valid name
+                self.number = 0;
+                return false;
+            }
         } else {
             if buf[13] != self.checksum {
                 // wrong LFN checksum
                 return false;
             }
 
-            let number = buf[0] & 0x0f;
+            let number = buf[0] & 0x1f;
 
             if number + 1 != self.number {
                 // wrong LFN order
@@ -111,19 +137,22 @@ impl <'a> LfnBuilder<'a> {
         let start = (self.number as usize - 1) * CHAR_ORDER.len();
 
         for (i, &n) in CHAR_ORDER.iter().enumerate() {
-            if buf[n] == 0x00 {
-                self.lfn.len = start + i;
+            let c = u16::from_le_bytes([buf[n], buf[n + 1]]);
+
+            if c == 0x0000 {
+                self.len = start + i;
                 break;
             }
 
-            self.lfn.buf[start + i] = buf[n];
+            self.units[start + i] = c;
         }
-        
-        return true;
+
+        true
     }
 
     pub fn build(&mut self) -> Option<(Stream, u8)> {
-        if self.number == 1 {
+        if self.number == 1 && self.len <= LFN_MAX_LEN {
+            self.lfn.decode(&self.units[..self.len]);
             Some((self.lfn_pos, self.checksum))
         } else {
             None
@@ -131,17 +160,36 @@ impl <'a> LfnBuilder<'a> {
     }
 }
 
-pub fn lfn_need_space(name: &str) -> usize {
-    let count = (name.len() + 1) / CHAR_ORDER.len();
+pub fn is_lfn_entry(buf: &[u8; DIR_ENTRY_SIZE]) -> bool {
+    buf[11] & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME
+}
 
-    if (name.len() + 1) % CHAR_ORDER.len() != 0 {
-        return count + 1;
-    }
+// the entries are stored last first, so this one starts a long name
+pub fn is_last_lfn_entry(buf: &[u8; DIR_ENTRY_SIZE]) -> bool {
+    buf[0] & LAST_LONG_ENTRY != 0
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= LFN_MAX_LEN
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
 
-    count
+pub fn lfn_need_space(name: &str) -> usize {
+    name.encode_utf16().count().div_ceil(CHAR_ORDER.len())
 }
 
-pub fn lfn_serialize<'a, F: FileSystem>(fs: &F, pos_to_write: &mut Stream, name: &str, crc: u8) -> Result<(), Error> {
+pub fn lfn_serialize<F: FileSystem>(fs: &F, pos_to_write: &mut Stream, name: &str, crc: u8) -> Result<(), Error> {
+    let mut units = [0u16; LFN_MAX_LEN];
+    let mut len = 0;
+
+    for c in name.encode_utf16() {
+        *units.get_mut(len).ok_or(Error::InvalidFileName)? = c;
+        len += 1;
+    }
+
     let count = lfn_need_space(name);
 
     for i in (0..count).rev() {
@@ -155,19 +203,112 @@ pub fn lfn_serialize<'a, F: FileSystem>(fs: &F, pos_to_write: &mut Stream, name:
         dir_entry[11] = ATTR_LONG_NAME;
         dir_entry[13] = crc;
 
-        let start = i * CHAR_ORDER.len();
-        let range = core::cmp::min(name.len() - start, CHAR_ORDER.len());
-        let end = start + range;
+        // the name is terminated by 0x0000 unless it fills the entry, the rest is padding
+        for (j, &n) in CHAR_ORDER.iter().enumerate() {
+            let c = match (i * CHAR_ORDER.len() + j).cmp(&len) {
+                core::cmp::Ordering::Less => units[i * CHAR_ORDER.len() + j],
+                core::cmp::Ordering::Equal => 0x0000,
+                core::cmp::Ordering::Greater => LFN_PADDING,
+            };
 
-        for (i, c) in name[start..end].chars().enumerate() {
-            dir_entry[CHAR_ORDER[i]] = c as u8;
+            dir_entry[n..n + 2].copy_from_slice(&c.to_le_bytes());
         }
 
-//        if range != CHAR_ORDER.len() {
-//        
-//        }
-        pos_to_write.write(fs, &dir_entry)?;
+        pos_to_write.write_dir(fs, &dir_entry)?;
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_device::RamDevice;
+    use crate::fs::Fs;
+
+    fn fs() -> Fs<RamDevice> {
+        let dev = RamDevice::new(2048, 512);
+        Fs::format(&dev).unwrap();
+        Fs::mount(dev).unwrap()
+    }
+
+    fn serialize(fs: &Fs<RamDevice>, name: &str) -> [[u8; DIR_ENTRY_SIZE]; 4] {
+        let mut entries = [[0u8; DIR_ENTRY_SIZE]; 4];
+        lfn_serialize(fs, &mut Stream::open(0), name, 0x5A).unwrap();
+
+        let mut stream = Stream::open(0);
+
+        for entry in entries.iter_mut().take(lfn_need_space(name)) {
+            stream.read(fs, entry).unwrap();
+        }
+
+        entries
+    }
+
+    fn unit(entry: &[u8; DIR_ENTRY_SIZE], n: usize) -> u16 {
+        u16::from_le_bytes([entry[CHAR_ORDER[n]], entry[CHAR_ORDER[n] + 1]])
+    }
+
+    fn round_trip(name: &str) -> Option<Lfn> {
+        let fs = fs();
+        let entries = serialize(&fs, name);
+        let mut lfn = Lfn::new();
+        let mut builder = LfnBuilder::new(&mut lfn);
+
+        for entry in entries.iter().take(lfn_need_space(name)) {
+            assert!(builder.process(Stream::open(0), entry));
+        }
+
+        let (_, checksum) = builder.build()?;
+        assert_eq!(checksum, 0x5A);
+        Some(lfn)
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        for name in ["\u{1F600}.txt", "music \u{1D11E}\u{1D11E} notes", "\u{10FFFF}"] {
+            assert_eq!(round_trip(name).unwrap().name(), name);
+        }
+    }
+
+    #[test]
+    fn unpaired_surrogate() {
+        let mut lfn = Lfn::new();
+        lfn.decode(&[0x61, 0xD800, 0x62, 0xDC00]);
+        assert_eq!(lfn.name(), "a\u{FFFD}b\u{FFFD}");
+    }
+
+    #[test]
+    fn padding() {
+        let fs = fs();
+        // 14 units, the pair is split between the entries
+        let entries = serialize(&fs, "abcdefghijkl\u{1F600}");
+
+        assert_eq!(entries[0][0], LAST_LONG_ENTRY | 2);
+        assert_eq!(unit(&entries[0], 0), 0xDE00);
+        assert_eq!(unit(&entries[0], 1), 0x0000);
+
+        for n in 2..CHAR_ORDER.len() {
+            assert_eq!(unit(&entries[0], n), LFN_PADDING);
+        }
+
+        assert_eq!(entries[1][0], 1);
+        assert_eq!(unit(&entries[1], 12), 0xD83D);
+    }
+
+    #[test]
+    fn no_terminator_when_full() {
+        let fs = fs();
+        let entries = serialize(&fs, "abcdefghijklm");
+        assert_eq!(entries[0][0], LAST_LONG_ENTRY | 1);
+        assert_eq!(unit(&entries[0], 12), b'm' as u16);
+        assert_eq!(round_trip("abcdefghijklm").unwrap().name(), "abcdefghijklm");
+    }
+
+    #[test]
+    fn too_long() {
+        let name = [b'a'; LFN_MAX_LEN + 1];
+        assert!(is_valid_name(core::str::from_utf8(&name[..LFN_MAX_LEN]).unwrap()));
+        assert!(!is_valid_name(core::str::from_utf8(&name).unwrap()));
+    }
+}
@@ -1,15 +1,28 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
 
 pub mod dir;
 mod dir_entry;
 mod stream;
 pub mod file;
 mod fat_table;
+mod fat_cache;
 mod path;
 mod dir_iterator;
 pub mod fs;
+pub mod format;
 pub mod block_device;
+pub mod cache;
+pub mod partition;
+pub mod gpt;
+pub mod code_page;
+pub mod time;
+pub mod metadata;
+pub mod check;
 mod lfn;
+mod short_name;
 
 #[derive(Debug)]
 pub enum Error {
@@ -23,6 +36,57 @@ pub enum Error {
     DirNotEmpty,
     ObjectAlreadyExist,
     DeviceIO,
+    UnsupportedSectorSize,
+    VolumeTooSmall,
+    FatTypeMismatch,
+    InvalidClusterSize,
+    InvalidFatsCount,
+    InvalidReservedSectors,
+    InvalidVolumeLabel,
+    InvalidOemName,
+    InvalidAlignment,
+    InvalidPartitionTable,
+    UnsupportedPartitionType,
+    OutOfBounds,
+    TooManyPartitions,
+    InvalidPartitionName,
+    InvalidFileName,
+    RootDirFull,
+    InvalidMove,
+    ReadOnly,
+    InvalidDateTime,
+    InvalidSeek,
+}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::NotFound => std::io::ErrorKind::NotFound,
+            Error::ObjectAlreadyExist => std::io::ErrorKind::AlreadyExists,
+            Error::ReadOnly => std::io::ErrorKind::PermissionDenied,
+            Error::UnexpectedEndOfFile => std::io::ErrorKind::UnexpectedEof,
+            Error::NotFile | Error::NotDir | Error::DirNotEmpty | Error::InvalidSeek => std::io::ErrorKind::InvalidInput,
+            Error::UnexpectedClusterValue | Error::InvalidClusterNumber => std::io::ErrorKind::InvalidData,
+            _ => std::io::ErrorKind::Other,
+        };
+
+        std::io::Error::new(kind, format!("{:?}", e))
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl embedded_io::Error for Error {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Error::NotFound => embedded_io::ErrorKind::NotFound,
+            Error::ObjectAlreadyExist => embedded_io::ErrorKind::AlreadyExists,
+            Error::ReadOnly => embedded_io::ErrorKind::PermissionDenied,
+            Error::NotFile | Error::NotDir | Error::DirNotEmpty | Error::InvalidSeek => embedded_io::ErrorKind::InvalidInput,
+            Error::UnexpectedClusterValue | Error::InvalidClusterNumber | Error::UnexpectedEndOfFile => embedded_io::ErrorKind::InvalidData,
+            _ => embedded_io::ErrorKind::Other,
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -40,12 +104,21 @@ pub enum ClusterValue {
 }
 
 pub trait FileSystem {
+    type CodePage: code_page::OemCodePage;
+
     fn root_cluster(&self) -> u32;
     fn cluster_count(&self) -> u32;
     fn cluster_size(&self) -> usize;
+    fn root_dir_size(&self) -> usize;
     fn read(&self, cluster: u32, offset: usize, buf: &mut [u8]) -> Result<usize, Error>;
     fn write(&self, cluster: u32, offset: usize, buf: &[u8]) -> Result<usize, Error>;
     fn fat_table_get(&self, cluster: u32) -> Result<ClusterValue, Error>;
     fn fat_table_set(&self, cluster: u32, value: ClusterValue) -> Result<(), Error>;
+    fn next_free(&self) -> u32;
+    fn cluster_allocated(&self, cluster: u32);
+    fn cluster_freed(&self, cluster: u32);
     fn flush(&self) -> Result<(), Error>;
+    fn code_page(&self) -> &Self::CodePage;
+    fn now(&self) -> time::DateTime;
+    fn updates_access_date(&self) -> bool;
 }
@@ -0,0 +1,154 @@
+use super::FileSystem;
+use super::dir_entry::{DirEntry, SHORT_NAME_BUF_SIZE, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM, ATTR_VOLUME_ID};
+use super::lfn::Lfn;
+use super::time::DateTime;
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Attributes(u8);
+
+impl Attributes {
+    pub const READ_ONLY: Attributes = Attributes(ATTR_READ_ONLY);
+    pub const HIDDEN: Attributes = Attributes(ATTR_HIDDEN);
+    pub const SYSTEM: Attributes = Attributes(ATTR_SYSTEM);
+    pub const ARCHIVE: Attributes = Attributes(ATTR_ARCHIVE);
+    pub const DIRECTORY: Attributes = Attributes(ATTR_DIRECTORY);
+
+    // the ones `Dir::set_attributes` can change
+    pub const SETTABLE: Attributes = Attributes(ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_ARCHIVE);
+
+    pub const fn empty() -> Self {
+        Attributes(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Attributes(bits)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Attributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(&self, other: Attributes) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn insert(&mut self, other: Attributes) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Attributes) {
+        self.0 &= !other.0;
+    }
+}
+
+impl core::ops::BitOr for Attributes {
+    type Output = Attributes;
+
+    fn bitor(self, other: Attributes) -> Attributes {
+        Attributes(self.0 | other.0)
+    }
+}
+
+impl core::ops::BitAnd for Attributes {
+    type Output = Attributes;
+
+    fn bitand(self, other: Attributes) -> Attributes {
+        Attributes(self.0 & other.0)
+    }
+}
+
+impl core::ops::Not for Attributes {
+    type Output = Attributes;
+
+    fn not(self) -> Attributes {
+        Attributes(!self.0)
+    }
+}
+
+pub struct Metadata {
+    long_name: Option<Lfn>,
+    short_name: [u8; SHORT_NAME_BUF_SIZE],
+    short_name_len: usize,
+    size: u32,
+    attributes: u8,
+    created: DateTime,
+    modified: DateTime,
+    accessed: DateTime,
+}
+
+impl Metadata {
+    pub(crate) fn new<F: FileSystem>(dir_entry: &DirEntry<F>, long_name: Option<Lfn>) -> Self {
+        let (short_name, short_name_len) = dir_entry.name();
+
+        Self {
+            long_name,
+            short_name,
+            short_name_len,
+            size: dir_entry.size(),
+            attributes: dir_entry.attributes(),
+            created: dir_entry.created(),
+            modified: dir_entry.modified(),
+            accessed: dir_entry.accessed(),
+        }
+    }
+
+    // the long name, or the short one for entries without it
+    pub fn name(&self) -> &str {
+        match &self.long_name {
+            Some(long_name) => long_name.name(),
+            None => self.short_name(),
+        }
+    }
+
+    pub fn long_name(&self) -> Option<&str> {
+        self.long_name.as_ref().map(|long_name| long_name.name())
+    }
+
+    pub fn short_name(&self) -> &str {
+        core::str::from_utf8(&self.short_name[..self.short_name_len]).unwrap_or("")
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        Attributes(self.attributes)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.attributes & ATTR_READ_ONLY != 0
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.attributes & ATTR_HIDDEN != 0
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.attributes & ATTR_SYSTEM != 0
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.attributes & (ATTR_VOLUME_ID | ATTR_DIRECTORY) == 0x00
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes & (ATTR_VOLUME_ID | ATTR_DIRECTORY) == ATTR_DIRECTORY
+    }
+
+    pub fn created(&self) -> DateTime {
+        self.created
+    }
+
+    pub fn modified(&self) -> DateTime {
+        self.modified
+    }
+
+    pub fn accessed(&self) -> DateTime {
+        self.accessed
+    }
+}
@@ -0,0 +1,559 @@
+use super::block_device::BlockDevice;
+use super::format::{self, FormatOptions};
+use super::{Error, FatType};
+
+pub const PARTITION_FAT12: u8 = 0x01;
+pub const PARTITION_FAT16_SMALL: u8 = 0x04;
+pub const PARTITION_FAT16: u8 = 0x06;
+pub const PARTITION_FAT32_CHS: u8 = 0x0B;
+pub const PARTITION_FAT32_LBA: u8 = 0x0C;
+pub const PARTITION_FAT16_LBA: u8 = 0x0E;
+pub const PARTITION_EXTENDED_CHS: u8 = 0x05;
+pub const PARTITION_EXTENDED_LBA: u8 = 0x0F;
+
+const MBR_SIZE: usize = 512;
+const MBR_PARTITION_TABLE: usize = 446;
+const MBR_PARTITION_ENTRY_SIZE: usize = 16;
+const MBR_PARTITIONS_COUNT: usize = 4;
+const PARTITION_ALIGNMENT: usize = 1024 * 1024;
+// CHS address past the end of the CHS range, partitions are found by LBA
+const CHS_OVERFLOW: [u8; 3] = [0xFE, 0xFF, 0xFF];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PartitionEntry {
+    bootable: bool,
+    partition_type: u8,
+    start: u32,
+    count: u32,
+}
+
+impl PartitionEntry {
+    fn from_raw(raw: &[u8]) -> Self {
+        Self {
+            bootable: raw[0] == 0x80,
+            partition_type: raw[4],
+            start: u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]),
+            count: u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]),
+        }
+    }
+
+    pub fn is_bootable(&self) -> bool {
+        self.bootable
+    }
+
+    pub fn partition_type(&self) -> u8 {
+        self.partition_type
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn is_fat(&self) -> bool {
+        matches!(self.partition_type,
+            PARTITION_FAT12 | PARTITION_FAT16_SMALL | PARTITION_FAT16 |
+            PARTITION_FAT32_CHS | PARTITION_FAT32_LBA | PARTITION_FAT16_LBA)
+    }
+
+    pub fn is_extended(&self) -> bool {
+        matches!(self.partition_type, PARTITION_EXTENDED_CHS | PARTITION_EXTENDED_LBA)
+    }
+}
+
+// block device limited to the sectors of one partition
+pub struct Volume<'a, D> {
+    dev: &'a D,
+    start: u32,
+    count: u32,
+    lba_size: usize,
+}
+
+impl <'a, D: BlockDevice> Volume<'a, D> {
+    pub fn new(dev: &'a D, start: u32, count: u32) -> Result<Self, Error> {
+        let dev_count = dev.count().or(Err(Error::DeviceIO))?;
+
+        if start as u64 + count as u64 > dev_count as u64 {
+            return Err(Error::OutOfBounds);
+        }
+
+        Ok(Self {
+            dev,
+            start,
+            count,
+            lba_size: dev.lba_size().or(Err(Error::DeviceIO))?,
+        })
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    fn check_bounds(&self, lba: u32, offset: usize, len: usize) -> Result<(), Error> {
+        let end = lba as u64 * self.lba_size as u64 + offset as u64 + len as u64;
+
+        if end > self.count as u64 * self.lba_size as u64 {
+            return Err(Error::OutOfBounds);
+        }
+
+        Ok(())
+    }
+}
+
+impl <'a, D: BlockDevice> BlockDevice for Volume<'a, D> {
+    type Error = Error;
+
+    fn read(&self, lba: u32, offset: usize, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.check_bounds(lba, offset, buf.len())?;
+        self.dev.read(lba + self.start, offset, buf).or(Err(Error::DeviceIO))
+    }
+
+    fn write(&self, lba: u32, offset: usize, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.check_bounds(lba, offset, buf.len())?;
+        self.dev.write(lba + self.start, offset, buf).or(Err(Error::DeviceIO))
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.dev.flush().or(Err(Error::DeviceIO))
+    }
+
+    fn count(&self) -> Result<u32, Self::Error> {
+        Ok(self.count)
+    }
+
+    fn lba_size(&self) -> Result<usize, Self::Error> {
+        Ok(self.lba_size)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PartitionOptions<'a> {
+    sectors: Option<u32>,
+    name: &'a str,
+    format_options: FormatOptions<'a>,
+}
+
+impl <'a> Default for PartitionOptions<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <'a> PartitionOptions<'a> {
+    pub fn new() -> Self {
+        Self {
+            sectors: None,
+            name: "",
+            format_options: FormatOptions::new(),
+        }
+    }
+
+    // without a size the partition takes the rest of the media
+    pub fn sectors(mut self, sectors: u32) -> Self {
+        self.sectors = Some(sectors);
+        self
+    }
+
+    // only stored in GPT
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn format_options(mut self, format_options: FormatOptions<'a>) -> Self {
+        self.format_options = format_options;
+        self
+    }
+
+    pub(crate) fn partition_name(&self) -> &'a str {
+        self.name
+    }
+
+    // first sector at or after `start` aligned to 1 MiB, and the sectors count
+    pub(crate) fn place(&self, lba_size: usize, start: u32, end: u32) -> Result<(u32, u32), Error> {
+        let alignment = core::cmp::max(1, PARTITION_ALIGNMENT / lba_size) as u64;
+        let start = (start as u64).div_ceil(alignment) * alignment;
+        let count = match self.sectors {
+            Some(sectors) => sectors as u64,
+            None => (end as u64).saturating_sub(start),
+        };
+
+        if count == 0 || start + count > end as u64 {
+            return Err(Error::OutOfBounds);
+        }
+
+        Ok((start as u32, count as u32))
+    }
+
+    pub(crate) fn format<D: BlockDevice>(&self, dev: &D, start: u32, count: u32) -> Result<FatType, Error> {
+        let volume = Volume::new(dev, start, count)?;
+        format::format_volume(&volume, &self.format_options.hidden_sectors(start))
+    }
+}
+
+// formats every partition and writes an MBR describing them
+pub fn create_mbr<D: BlockDevice>(dev: &D, partitions: &[PartitionOptions]) -> Result<(), Error> {
+    if partitions.len() > MBR_PARTITIONS_COUNT {
+        return Err(Error::TooManyPartitions);
+    }
+
+    let lba_size = dev.lba_size().or(Err(Error::DeviceIO))?;
+    let end = dev.count().or(Err(Error::DeviceIO))?;
+    let mut mbr = [0u8; MBR_SIZE];
+    let mut next = 1;
+
+    // a GPT header left behind would be found before the new table
+    if end > 1 {
+        format::zero_sector(dev, 1, lba_size as u32)?;
+    }
+
+    for (i, partition) in partitions.iter().enumerate() {
+        let (start, count) = partition.place(lba_size, next, end)?;
+        let fat_type = partition.format(dev, start, count)?;
+        let raw = &mut mbr[MBR_PARTITION_TABLE + i * MBR_PARTITION_ENTRY_SIZE..MBR_PARTITION_TABLE + (i + 1) * MBR_PARTITION_ENTRY_SIZE];
+
+        raw[1..4].copy_from_slice(&CHS_OVERFLOW);
+        raw[4] = match fat_type {
+            FatType::Fat12 => PARTITION_FAT12,
+            FatType::Fat16 => PARTITION_FAT16_LBA,
+            FatType::Fat32 => PARTITION_FAT32_LBA,
+        };
+        raw[5..8].copy_from_slice(&CHS_OVERFLOW);
+        raw[8..12].copy_from_slice(&start.to_le_bytes());
+        raw[12..16].copy_from_slice(&count.to_le_bytes());
+        next = start + count;
+    }
+
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+
+    if lba_size > MBR_SIZE {
+        format::zero_sector(dev, 0, lba_size as u32)?;
+    }
+
+    dev.write(0, 0, &mbr).or(Err(Error::DeviceIO))?;
+    dev.flush().or(Err(Error::DeviceIO))
+}
+
+pub struct Drive<D> {
+    drive: D,
+    partitions: [Option<PartitionEntry>; MBR_PARTITIONS_COUNT],
+    is_superfloppy: bool,
+}
+
+impl <D: BlockDevice> Drive<D> {
+    pub fn new(drive: D) -> Result<Self, Error> {
+        let mut mbr = [0u8; MBR_SIZE];
+        drive.read(0, 0, &mut mbr).or(Err(Error::DeviceIO))?;
+
+        if mbr[510] != 0x55 || mbr[511] != 0xAA {
+            return Err(Error::InvalidPartitionTable);
+        }
+
+        let mut partitions = [None; MBR_PARTITIONS_COUNT];
+
+        // media formatted without a partition table starts with a boot sector
+        if is_boot_sector(&mbr) {
+            return Ok(Self {
+                drive,
+                partitions,
+                is_superfloppy: true,
+            });
+        }
+
+        for (i, partition) in partitions.iter_mut().enumerate() {
+            let offset = MBR_PARTITION_TABLE + i * MBR_PARTITION_ENTRY_SIZE;
+            let raw = &mbr[offset..offset + MBR_PARTITION_ENTRY_SIZE];
+
+            if raw[0] != 0x00 && raw[0] != 0x80 {
+                return Err(Error::InvalidPartitionTable);
+            }
+
+            let entry = PartitionEntry::from_raw(raw);
+
+            if entry.partition_type != 0 && entry.count != 0 {
+                *partition = Some(entry);
+            }
+        }
+
+        Ok(Self {
+            drive,
+            partitions,
+            is_superfloppy: false,
+        })
+    }
+
+    pub fn is_superfloppy(&self) -> bool {
+        self.is_superfloppy
+    }
+
+    pub fn partition(&self, n: usize) -> Option<PartitionEntry> {
+        *self.partitions.get(n)?
+    }
+
+    pub fn partitions(&self) -> impl Iterator<Item = PartitionEntry> + '_ {
+        self.partitions.iter().flatten().copied()
+    }
+
+    pub fn volume(&self, n: usize) -> Result<Volume<'_, D>, Error> {
+        if self.is_superfloppy {
+            if n != 0 {
+                return Err(Error::NotFound);
+            }
+
+            let count = self.drive.count().or(Err(Error::DeviceIO))?;
+            return Volume::new(&self.drive, 0, count);
+        }
+
+        let entry = self.partition(n).ok_or(Error::NotFound)?;
+
+        if !entry.is_fat() {
+            return Err(Error::UnsupportedPartitionType);
+        }
+
+        Volume::new(&self.drive, entry.start, entry.count)
+    }
+
+    pub fn logical_partitions(&self) -> LogicalPartitions<'_, D> {
+        let extended = self.partitions().find(|p| p.is_extended());
+        LogicalPartitions::new(&self.drive, extended)
+    }
+
+    // FAT volumes in the extended partition, other logical partitions are skipped
+    pub fn logical_volumes(&self) -> impl Iterator<Item = Result<Volume<'_, D>, Error>> + '_ {
+        self.logical_partitions()
+            .filter(|p| p.as_ref().map_or(true, |p| p.is_fat()))
+            .map(|p| p.and_then(|p| Volume::new(&self.drive, p.start, p.count)))
+    }
+
+    pub fn into_inner(self) -> D {
+        self.drive
+    }
+}
+
+// walks the EBR chain of an extended partition, the entries have absolute LBAs
+pub struct LogicalPartitions<'a, D> {
+    drive: &'a D,
+    extended_start: u32,
+    extended_count: u32,
+    next: Option<u32>,
+    error: Option<Error>,
+}
+
+impl <'a, D: BlockDevice> LogicalPartitions<'a, D> {
+    fn new(drive: &'a D, extended: Option<PartitionEntry>) -> Self {
+        let (extended_start, extended_count) = extended.map_or((0, 0), |p| (p.start, p.count));
+
+        let mut partitions = Self {
+            drive,
+            extended_start,
+            extended_count,
+            next: extended.map(|_| 0),
+            error: None,
+        };
+
+        // a looping chain would give the same partitions again before the loop
+        // shows up, so the links are checked before anything is returned
+        if let Err(e) = partitions.check_links() {
+            partitions.next = None;
+            partitions.error = Some(e);
+        }
+
+        partitions
+    }
+
+    // Brent's cycle detection, it needs no memory for the EBRs already seen
+    fn check_links(&self) -> Result<(), Error> {
+        let mut next = self.next;
+        let mut tortoise = u32::MAX;
+        let mut power = 1;
+        let mut steps = 0;
+
+        while let Some(offset) = next {
+            if offset == tortoise {
+                return Err(Error::InvalidPartitionTable);
+            }
+
+            steps += 1;
+
+            if steps == power {
+                tortoise = offset;
+                power *= 2;
+                steps = 0;
+            }
+
+            next = self.read_ebr(offset)?.1;
+        }
+
+        Ok(())
+    }
+
+    // the logical partition entry of the EBR at `offset` and where the next EBR is
+    fn read_ebr(&self, offset: u32) -> Result<(PartitionEntry, Option<u32>), Error> {
+        let mut ebr = [0u8; MBR_SIZE];
+        self.drive.read(self.extended_start + offset, 0, &mut ebr).or(Err(Error::DeviceIO))?;
+
+        if ebr[510] != 0x55 || ebr[511] != 0xAA {
+            return Err(Error::InvalidPartitionTable);
+        }
+
+        let logical = PartitionEntry::from_raw(&ebr[MBR_PARTITION_TABLE..MBR_PARTITION_TABLE + MBR_PARTITION_ENTRY_SIZE]);
+        let link = PartitionEntry::from_raw(&ebr[MBR_PARTITION_TABLE + MBR_PARTITION_ENTRY_SIZE..MBR_PARTITION_TABLE + 2 * MBR_PARTITION_ENTRY_SIZE]);
+
+        // link is relative to the extended partition, the logical partition to its EBR
+        let next = if link.is_extended() && link.count != 0 {
+            if link.start == 0 {
+                // points back at the first EBR
+                return Err(Error::InvalidPartitionTable);
+            }
+
+            if link.start >= self.extended_count {
+                return Err(Error::OutOfBounds);
+            }
+
+            Some(link.start)
+        } else {
+            None
+        };
+
+        Ok((logical, next))
+    }
+
+    fn logical_partition(&self, offset: u32, logical: PartitionEntry) -> Result<Option<PartitionEntry>, Error> {
+        if logical.partition_type == 0 || logical.count == 0 {
+            return Ok(None);
+        }
+
+        let end = offset as u64 + logical.start as u64 + logical.count as u64;
+
+        if logical.start == 0 || end > self.extended_count as u64 {
+            return Err(Error::OutOfBounds);
+        }
+
+        Ok(Some(PartitionEntry {
+            start: self.extended_start + offset + logical.start,
+            ..logical
+        }))
+    }
+}
+
+impl <'a, D: BlockDevice> Iterator for LogicalPartitions<'a, D> {
+    type Item = Result<PartitionEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+
+        while let Some(offset) = self.next.take() {
+            let result = self.read_ebr(offset).and_then(|(logical, next)| {
+                self.next = next;
+                self.logical_partition(offset, logical)
+            });
+
+            match result {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.next = None;
+                    return Some(Err(e));
+                },
+            }
+        }
+
+        None
+    }
+}
+
+fn is_boot_sector(raw: &[u8; MBR_SIZE]) -> bool {
+    let sector_size = u16::from_le_bytes([raw[11], raw[12]]);
+    let sectors_in_cluster = raw[13];
+    let reserved_sectors_count = u16::from_le_bytes([raw[14], raw[15]]);
+    let fats_count = raw[16];
+
+    (raw[0] == 0xEB || raw[0] == 0xE9)
+        && matches!(sector_size, 512 | 1024 | 2048 | 4096)
+        && sectors_in_cluster.is_power_of_two()
+        && reserved_sectors_count != 0
+        && fats_count != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_device::RamDevice;
+
+    const EXTENDED_START: u32 = 2048;
+    const EXTENDED_COUNT: u32 = 60000;
+
+    fn write_entry(dev: &RamDevice, sector: u32, n: usize, partition_type: u8, start: u32, count: u32) {
+        let mut raw = [0u8; MBR_PARTITION_ENTRY_SIZE];
+        raw[4] = partition_type;
+        raw[8..12].copy_from_slice(&start.to_le_bytes());
+        raw[12..16].copy_from_slice(&count.to_le_bytes());
+        dev.write(sector, MBR_PARTITION_TABLE + n * MBR_PARTITION_ENTRY_SIZE, &raw).unwrap();
+        dev.write(sector, 510, &[0x55, 0xAA]).unwrap();
+    }
+
+    // one EBR for each `(offset, link)`, its logical partition takes the 100
+    // sectors after the first 63
+    fn extended_drive(ebrs: &[(u32, u32)]) -> Drive<RamDevice> {
+        let dev = RamDevice::new(EXTENDED_START + EXTENDED_COUNT, 512);
+        write_entry(&dev, 0, 0, PARTITION_EXTENDED_LBA, EXTENDED_START, EXTENDED_COUNT);
+
+        for &(offset, link) in ebrs {
+            write_entry(&dev, EXTENDED_START + offset, 0, PARTITION_FAT16, 63, 100);
+
+            if link != 0 {
+                write_entry(&dev, EXTENDED_START + offset, 1, PARTITION_EXTENDED_CHS, link, 163);
+            }
+        }
+
+        Drive::new(dev).unwrap()
+    }
+
+    #[test]
+    fn chain() {
+        let drive = extended_drive(&[(0, 20000), (20000, 40000), (40000, 0)]);
+        let mut partitions = drive.logical_partitions();
+
+        for offset in [0, 20000, 40000] {
+            let entry = partitions.next().unwrap().unwrap();
+            assert_eq!(entry.start(), EXTENDED_START + offset + 63);
+            assert_eq!(entry.count(), 100);
+        }
+
+        assert!(partitions.next().is_none());
+    }
+
+    #[test]
+    fn looping_chain() {
+        let drive = extended_drive(&[(0, 20000), (20000, 40000), (40000, 20000)]);
+        let mut partitions = drive.logical_partitions();
+        assert!(matches!(partitions.next(), Some(Err(Error::InvalidPartitionTable))));
+        assert!(partitions.next().is_none());
+
+        let self_link = extended_drive(&[(0, 20000), (20000, 20000)]);
+        assert_eq!(self_link.logical_partitions().count(), 1);
+    }
+
+    #[test]
+    fn link_past_extended_partition() {
+        let drive = extended_drive(&[(0, 20000), (20000, EXTENDED_COUNT)]);
+        let mut partitions = drive.logical_partitions();
+        assert!(matches!(partitions.next(), Some(Err(Error::OutOfBounds))));
+        assert!(partitions.next().is_none());
+    }
+
+    #[test]
+    fn logical_partition_past_extended_partition() {
+        let drive = extended_drive(&[(0, EXTENDED_COUNT - 150), (EXTENDED_COUNT - 150, 0)]);
+        let mut partitions = drive.logical_partitions();
+        assert!(partitions.next().unwrap().is_ok());
+        assert!(matches!(partitions.next(), Some(Err(Error::OutOfBounds))));
+        assert!(partitions.next().is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use super::{FileSystem, Error};
+use super::Error;
 
 pub struct Path<'a> {
     path: &'a str,
@@ -7,10 +7,10 @@ pub struct Path<'a> {
 }
 
 impl <'a> Path<'a> {
-    pub fn new<F: FileSystem>(path: &'a str) -> Result<Self, Error> {
+    pub fn new(path: &'a str) -> Result<Self, Error> {
         let mut name_index = 0;
 
-        for (i, c) in path.chars().enumerate() {
+        for (i, c) in path.char_indices() {
             if c == '\\' || c == '/' {
                 name_index = i + 1;
             }
@@ -33,7 +33,7 @@ impl <'a> Iterator for Path<'a>
     type Item = &'a str;
 
     fn next(&mut self) -> Option<Self::Item> {
-        for (i, c) in (&self.path[self.path_index..self.name_index]).chars().enumerate() {
+        for (i, c) in self.path[self.path_index..self.name_index].char_indices() {
             if c == '\\' || c == '/' {
                 let start = self.path_index;
                 let end = start + i;
@@ -0,0 +1,220 @@
+use super::code_page::OemCodePage;
+use super::dir_entry::{NT_RES_LOWER_BASE, NT_RES_LOWER_EXT};
+
+// 8.3 names as stored in dir entries, 8 chars of name and 3 of extension padded with spaces
+pub const SHORT_NAME_LEN: usize = 11;
+const BASE_LEN: usize = 8;
+pub const MAX_TAIL: u32 = 999999;
+
+const SPECIAL_CHARS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+// basis name of the Microsoft algorithm, `true` when the name didn't fit as it is
+pub fn basis_name<C: OemCodePage>(name: &str, code_page: &C) -> ([u8; SHORT_NAME_LEN], bool) {
+    let mut raw = [b' '; SHORT_NAME_LEN];
+    let trimmed = name.trim_start_matches('.');
+    let mut is_lossy = trimmed.len() != name.len();
+
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (trimmed, ""),
+    };
+
+    is_lossy |= copy_chars(&mut raw[..BASE_LEN], base, code_page);
+    is_lossy |= copy_chars(&mut raw[BASE_LEN..], ext, code_page);
+
+    if raw[0] == b' ' {
+        raw[0] = b'_';
+        is_lossy = true;
+    }
+
+    (raw, is_lossy)
+}
+
+// copies upper cased chars, spaces and periods are dropped and anything else
+// not allowed in short names or missing in the code page is replaced by '_',
+// returns `true` if anything got lost
+fn copy_chars<C: OemCodePage>(buf: &mut [u8], name: &str, code_page: &C) -> bool {
+    let mut is_lossy = false;
+    let mut len = 0;
+
+    for c in name.chars() {
+        if c == ' ' || c == '.' {
+            is_lossy = true;
+            continue;
+        }
+
+        if len == buf.len() {
+            return true;
+        }
+
+        let c = to_upper_case(c);
+
+        let byte = if c.is_ascii() {
+            Some(c as u8).filter(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || SPECIAL_CHARS.contains(c))
+        } else {
+            code_page.encode(c)
+        };
+
+        buf[len] = byte.unwrap_or_else(|| {
+            is_lossy = true;
+            b'_'
+        });
+
+        len += 1;
+    }
+
+    is_lossy
+}
+
+fn to_upper_case(c: char) -> char {
+    let mut upper = c.to_uppercase();
+
+    match (upper.next(), upper.next()) {
+        (Some(upper), None) => upper,
+        _ => c,
+    }
+}
+
+// basis name with "~n" appended, the name gets cut to keep it in 8 chars
+pub fn numeric_tail(basis: &[u8; SHORT_NAME_LEN], n: u32) -> [u8; SHORT_NAME_LEN] {
+    let mut digits = [0u8; 7];
+    let mut digits_len = 0;
+    let mut n = n;
+
+    loop {
+        digits[digits.len() - 1 - digits_len] = b'0' + (n % 10) as u8;
+        digits_len += 1;
+        n /= 10;
+
+        if n == 0 {
+            break;
+        }
+    }
+
+    digits[digits.len() - 1 - digits_len] = b'~';
+    let tail = &digits[digits.len() - 1 - digits_len..];
+
+    let base_len = basis[..BASE_LEN].iter().position(|&c| c == b' ').unwrap_or(BASE_LEN);
+    let keep = core::cmp::min(base_len, BASE_LEN - tail.len());
+    let mut raw = *basis;
+
+    raw[keep..keep + tail.len()].copy_from_slice(tail);
+    raw[keep + tail.len()..BASE_LEN].fill(b' ');
+    raw
+}
+
+// number n of a short name made by `numeric_tail(basis, n)`
+pub fn tail_number(basis: &[u8; SHORT_NAME_LEN], raw: &[u8]) -> Option<u32> {
+    let base = &raw[..BASE_LEN];
+    let base_len = base.iter().position(|&c| c == b' ').unwrap_or(BASE_LEN);
+    let tilde = base[..base_len].iter().rposition(|&c| c == b'~')?;
+    let digits = &base[tilde + 1..base_len];
+
+    if digits.is_empty() || digits[0] == b'0' || !digits.iter().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let n = digits.iter().fold(0, |n, &c| n * 10 + (c - b'0') as u32);
+
+    if n > MAX_TAIL || numeric_tail(basis, n)[..] != raw[..SHORT_NAME_LEN] {
+        return None;
+    }
+
+    Some(n)
+}
+
+// NTRes flags showing the short name as exactly `name`, `None` if it takes a long name
+pub fn case_flags<C: OemCodePage>(raw: &[u8; SHORT_NAME_LEN], name: &str, code_page: &C) -> Option<u8> {
+    let base_len = raw[..BASE_LEN].iter().position(|&c| c == b' ').unwrap_or(BASE_LEN);
+    let ext_len = raw[BASE_LEN..].iter().position(|&c| c == b' ').unwrap_or(SHORT_NAME_LEN - BASE_LEN);
+    let mut chars = name.chars();
+    let mut flags = 0;
+
+    if is_lower_case(&raw[..base_len], &mut chars, code_page)? {
+        flags |= NT_RES_LOWER_BASE;
+    }
+
+    if ext_len != 0 {
+        if chars.next() != Some('.') {
+            return None;
+        }
+
+        if is_lower_case(&raw[BASE_LEN..BASE_LEN + ext_len], &mut chars, code_page)? {
+            flags |= NT_RES_LOWER_EXT;
+        }
+    }
+
+    if chars.next().is_some() {
+        return None;
+    }
+
+    Some(flags)
+}
+
+// a part of a short name can be all upper or all lower case, never mixed
+fn is_lower_case<C: OemCodePage>(raw: &[u8], chars: &mut core::str::Chars, code_page: &C) -> Option<bool> {
+    let mut is_upper = true;
+    let mut is_lower = true;
+
+    for &c in raw {
+        let name_c = chars.next()?;
+        let c = code_page.decode(c);
+        is_upper &= name_c == c;
+        // other systems may lower case the rest of the code page differently
+        is_lower &= c.is_ascii() && name_c == c.to_ascii_lowercase();
+    }
+
+    match (is_upper, is_lower) {
+        (true, _) => Some(false),
+        (false, true) => Some(true),
+        (false, false) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code_page::Cp437;
+
+    fn basis(name: &str) -> ([u8; SHORT_NAME_LEN], bool) {
+        basis_name(name, &Cp437)
+    }
+
+    #[test]
+    fn basis_names() {
+        assert_eq!(basis("readme.txt"), (*b"README  TXT", false));
+        assert_eq!(basis("FOO"), (*b"FOO        ", false));
+        assert_eq!(basis("long file name.html"), (*b"LONGFILEHTM", true));
+        assert_eq!(basis("a.b.c"), (*b"AB      C  ", true));
+        assert_eq!(basis(".profile"), (*b"PROFILE    ", true));
+        assert_eq!(basis("a+b=c.txt"), (*b"A_B_C   TXT", true));
+        assert_eq!(basis("caf\u{e9}.txt"), (*b"CAF\x90    TXT", false));
+        assert_eq!(basis("\u{4e2d}.txt"), (*b"_       TXT", true));
+        assert_eq!(basis("..."), (*b"_          ", true));
+    }
+
+    #[test]
+    fn numeric_tails() {
+        assert_eq!(&numeric_tail(b"README  TXT", 1), b"README~1TXT");
+        assert_eq!(&numeric_tail(b"LONGFILEHTM", 1), b"LONGFI~1HTM");
+        assert_eq!(&numeric_tail(b"LONGFILEHTM", 42), b"LONGF~42HTM");
+        assert_eq!(&numeric_tail(b"LONGFILEHTM", MAX_TAIL), b"L~999999HTM");
+        assert_eq!(&numeric_tail(b"AB      C  ", 10), b"AB~10   C  ");
+    }
+
+    #[test]
+    fn tail_numbers() {
+        let basis = *b"LONGFILEHTM";
+
+        for n in [1, 9, 10, 4567, MAX_TAIL] {
+            assert_eq!(tail_number(&basis, &numeric_tail(&basis, n)), Some(n));
+        }
+
+        assert_eq!(tail_number(&basis, b"LONGFILEHTM"), None);
+        assert_eq!(tail_number(&basis, b"LONGFI~0HTM"), None);
+        assert_eq!(tail_number(&basis, b"LONGF~01HTM"), None);
+        assert_eq!(tail_number(&basis, b"LONGFI~1TXT"), None);
+        assert_eq!(tail_number(&basis, b"OTHERF~1HTM"), None);
+        assert_eq!(tail_number(&basis, b"LONGFI~ HTM"), None);
+    }
+}
@@ -13,6 +13,12 @@ impl Stream {
         Ok(Self::open(cluster))
     }
 
+    pub fn create_dir<F: FileSystem>(fs: &F) -> Result<Self, Error> {
+        let cluster = fat_table::create(fs)?;
+        fat_table::zero(fs, cluster)?;
+        Ok(Self::open(cluster))
+    }
+
     pub fn open(cluster: u32) -> Self {
         Self {
             cluster,
@@ -21,14 +27,32 @@ impl Stream {
     }
 
     pub fn remove<F: FileSystem>(fs: &F, cluster: u32) -> Result<(), Error> {
+        // empty files may have no cluster at all
+        if cluster == 0 {
+            return Ok(());
+        }
+
         fat_table::remove(fs, cluster)
     }
 
     pub fn write<F: FileSystem>(&mut self, fs: &F, buf: &[u8]) -> Result<usize, Error> {
+        self.write_with(fs, buf, false)
+    }
+
+    // same as `write`, but the clusters a directory grows by get zeroed
+    pub fn write_dir<F: FileSystem>(&mut self, fs: &F, buf: &[u8]) -> Result<usize, Error> {
+        self.write_with(fs, buf, true)
+    }
+
+    fn write_with<F: FileSystem>(&mut self, fs: &F, buf: &[u8], is_dir: bool) -> Result<usize, Error> {
         let mut bytes_written = 0;
 
         while bytes_written != buf.len() {
-            if self.offset == fs.cluster_size() {
+            if self.offset == cluster_size(fs, self.cluster) {
+                if self.cluster == 0 {
+                    return Err(Error::RootDirFull);
+                }
+
                 match fs.fat_table_get(self.cluster)? {
                     ClusterValue::Next(cluster) => {
                         self.cluster = cluster;
@@ -36,6 +60,11 @@ impl Stream {
                     ClusterValue::Last => {
                         // extend cluster chain
                         let cluster = fat_table::extend(fs, self.cluster)?;
+
+                        if is_dir {
+                            fat_table::zero(fs, cluster)?;
+                        }
+
                         self.cluster = cluster;
                     },
                     ClusterValue::Bad | ClusterValue::Free => {
@@ -57,7 +86,11 @@ impl Stream {
         let mut bytes_read = 0;
 
         while bytes_read != buf.len() {
-            if self.offset == fs.cluster_size() {
+            if self.offset == cluster_size(fs, self.cluster) {
+                if self.cluster == 0 {
+                    break;
+                }
+
                 match fs.fat_table_get(self.cluster)? {
                     ClusterValue::Next(cluster) => {
                         self.cluster = cluster;
@@ -80,6 +113,37 @@ impl Stream {
         Ok(bytes_read)
     }
 
+    // moves from byte `current` of the chain starting at `origin` to byte `pos`
+    pub fn seek<F: FileSystem>(&mut self, fs: &F, origin: u32, current: u32, pos: u32) -> Result<(), Error> {
+        let cluster_size = fs.cluster_size() as u32;
+        let (current_index, _) = cluster_index(current, cluster_size);
+        let (pos_index, offset) = cluster_index(pos, cluster_size);
+
+        let (mut cluster, clusters_to_skip) = if pos_index >= current_index {
+            (self.cluster, pos_index - current_index)
+        } else {
+            (origin, pos_index)
+        };
+
+        for _ in 0..clusters_to_skip {
+            match fs.fat_table_get(cluster)? {
+                ClusterValue::Next(next_cluster) => {
+                    cluster = next_cluster;
+                },
+                ClusterValue::Last => {
+                    return Err(Error::UnexpectedEndOfFile);
+                },
+                ClusterValue::Free | ClusterValue::Bad => {
+                    return Err(Error::UnexpectedClusterValue);
+                },
+            }
+        }
+
+        self.cluster = cluster;
+        self.offset = offset as usize;
+        Ok(())
+    }
+
     pub fn truncate<F: FileSystem>(&mut self, fs: &F) -> Result<(), Error> {
         fat_table::truncate(fs, self.cluster)
     }
@@ -91,4 +155,24 @@ impl Stream {
     pub fn is_equal(&self, pos: &Stream) -> bool {
         self.cluster == pos.cluster && self.offset == pos.offset
     }
-}
\ No newline at end of file
+}
+
+// the FAT12/16 root directory is a fixed region addressed as cluster 0, it can't grow
+fn cluster_size<F: FileSystem>(fs: &F, cluster: u32) -> usize {
+    if cluster == 0 {
+        fs.root_dir_size()
+    } else {
+        fs.cluster_size()
+    }
+}
+
+// a position on a cluster boundary stays at the end of the previous cluster,
+// the same way read and write leave it, so the next cluster isn't required to exist
+fn cluster_index(pos: u32, cluster_size: u32) -> (u32, u32) {
+    if pos == 0 {
+        (0, 0)
+    } else {
+        let index = (pos - 1) / cluster_size;
+        (index, pos - index * cluster_size)
+    }
+}
@@ -0,0 +1,222 @@
+// dates in dir entries count years from 1980 in 7 bits
+const FIRST_YEAR: u16 = 1980;
+const LAST_YEAR: u16 = 2107;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DateTime {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    millis: u16,
+}
+
+impl DateTime {
+    // FAT has no time zones, the time is stored as it is given
+    pub const fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8, millis: u16) -> Self {
+        Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            millis,
+        }
+    }
+
+    pub const EPOCH: DateTime = DateTime::new(FIRST_YEAR, 1, 1, 0, 0, 0, 0);
+
+    pub fn year(&self) -> u16 {
+        self.year
+    }
+
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    pub fn second(&self) -> u8 {
+        self.second
+    }
+
+    pub fn millis(&self) -> u16 {
+        self.millis
+    }
+
+    pub(crate) fn is_representable(&self) -> bool {
+        (FIRST_YEAR..=LAST_YEAR).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+            && self.millis < 1000
+    }
+
+    // date, time and the 10 ms units on top of the 2 s resolution of time,
+    // anything out of range ends up as the first representable date
+    pub(crate) fn to_raw(self) -> (u16, u16, u8) {
+        let dt = if self.is_representable() { self } else { Self::EPOCH };
+        let date = ((dt.year - FIRST_YEAR) << 9) | ((dt.month as u16) << 5) | dt.day as u16;
+        let time = ((dt.hour as u16) << 11) | ((dt.minute as u16) << 5) | (dt.second as u16 / 2);
+        let tenths = (dt.second % 2) * 100 + (dt.millis / 10) as u8;
+        (date, time, tenths)
+    }
+
+    pub(crate) fn from_raw(date: u16, time: u16, tenths: u8) -> Self {
+        let tenths = if tenths < 200 { tenths } else { 0 };
+
+        Self {
+            year: FIRST_YEAR + (date >> 9),
+            month: ((date >> 5) & 0x0f) as u8,
+            day: (date & 0x1f) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3f) as u8,
+            second: ((time & 0x1f) * 2) as u8 + tenths / 100,
+            millis: (tenths % 100) as u16 * 10,
+        }
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+pub trait TimeSource {
+    fn now(&self) -> DateTime;
+}
+
+// for devices without a clock, everything gets stamped with the FAT epoch
+#[derive(Clone, Copy, Default)]
+pub struct NoClock;
+
+impl TimeSource for NoClock {
+    fn now(&self) -> DateTime {
+        DateTime::EPOCH
+    }
+}
+
+// the system clock in UTC
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Default)]
+pub struct SystemTimeSource;
+
+#[cfg(feature = "std")]
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> DateTime {
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let secs = secs % 86400;
+
+        DateTime::new(
+            year.clamp(0, u16::MAX as i64) as u16,
+            month,
+            day,
+            (secs / 3600) as u8,
+            (secs / 60 % 60) as u8,
+            (secs % 60) as u8,
+            since_epoch.subsec_millis() as u16,
+        )
+    }
+}
+
+// days since 1970-01-01 to a date of the proleptic Gregorian calendar
+#[cfg(feature = "std")]
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_round_trip() {
+        for dt in [
+            DateTime::EPOCH,
+            DateTime::new(2024, 2, 29, 23, 59, 59, 990),
+            DateTime::new(2107, 12, 31, 12, 30, 1, 500),
+            DateTime::new(1999, 7, 4, 0, 0, 2, 0),
+        ] {
+            let (date, time, tenths) = dt.to_raw();
+            assert_eq!(DateTime::from_raw(date, time, tenths), dt);
+        }
+    }
+
+    #[test]
+    fn raw_fields() {
+        assert_eq!(DateTime::new(2024, 2, 29, 13, 45, 31, 250).to_raw(), (0x585D, 0x6DAF, 125));
+        assert_eq!(DateTime::EPOCH.to_raw(), (0x0021, 0x0000, 0));
+    }
+
+    #[test]
+    fn millis_below_resolution() {
+        let (date, time, tenths) = DateTime::new(2000, 1, 1, 0, 0, 0, 999).to_raw();
+        assert_eq!(DateTime::from_raw(date, time, tenths).millis(), 990);
+    }
+
+    #[test]
+    fn not_representable() {
+        for dt in [
+            DateTime::new(1979, 12, 31, 0, 0, 0, 0),
+            DateTime::new(2108, 1, 1, 0, 0, 0, 0),
+            DateTime::new(2023, 2, 29, 0, 0, 0, 0),
+            DateTime::new(2000, 13, 1, 0, 0, 0, 0),
+            DateTime::new(2000, 1, 1, 24, 0, 0, 0),
+        ] {
+            assert!(!dt.is_representable());
+            assert_eq!(dt.to_raw(), DateTime::EPOCH.to_raw());
+        }
+
+        assert!(DateTime::new(2000, 2, 29, 0, 0, 0, 0).is_representable());
+    }
+
+    #[test]
+    fn invalid_tenths() {
+        assert_eq!(DateTime::from_raw(0x0021, 0x0000, 200), DateTime::EPOCH);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(3652), (1980, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(50402), (2107, 12, 31));
+    }
+}